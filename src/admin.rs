@@ -0,0 +1,139 @@
+//! Admin endpoints for reviewing what the forwarder has seen
+//!
+//! All admin endpoints require the `ADMIN_TOKEN` secret as bearer token. They
+//! are disabled if the secret is not set.
+
+use crate::unknown::{self, Entry};
+use crate::{directory, subscribers};
+use serde_json::{json, Value};
+use url::Url;
+use worker::{Env, Result};
+
+/// Number of entries returned if the request has no `limit` parameter
+const DEFAULT_LIMIT: usize = 100;
+
+/// Check the `Authorization` header of a request against the admin token
+///
+/// The comparison takes the same time for every token of the same length,
+/// so the token can't be guessed byte by byte.
+#[must_use]
+pub fn authorized(authorization: Option<&str>, token: Option<&str>) -> bool {
+    let (Some(authorization), Some(token)) = (authorization, token) else {
+        return false;
+    };
+    let Some(given) = authorization.trim().strip_prefix("Bearer ") else {
+        return false;
+    };
+    !token.is_empty()
+        && given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Read the `limit` query parameter
+fn limit(url: &Url) -> usize {
+    url.query_pairs()
+        .find(|(key, _)| key == "limit")
+        .and_then(|(_, limit)| limit.parse().ok())
+        .unwrap_or(DEFAULT_LIMIT)
+}
+
+/// Report of unknown user agents for the given entries
+///
+/// With `format=opawg`, the report is a list of suggested entries for the
+/// OPAWG user agent list instead.
+fn report(url: &Url, entries: &[Entry]) -> Value {
+    let opawg = url
+        .query_pairs()
+        .any(|(key, value)| key == "format" && value == "opawg");
+    if opawg {
+        return Value::Array(entries.iter().map(Entry::opawg).collect());
+    }
+    json!({ "user_agents": entries })
+}
+
+/// Report of the most common unknown user agents
+///
+/// # Errors
+///
+/// * the counts can't be read from the `UnknownUserAgents` Durable Object
+pub async fn unknown_user_agents(env: &Env, url: &Url) -> Result<Value> {
+    Ok(report(url, &unknown::top(env, limit(url)).await?))
+}
+
+/// Report of the last crawls of podcast directories and the latest version
+/// of the upstream feed
+///
+/// # Errors
+///
+/// * the crawls can't be read from the `Directories` Durable Object
+pub async fn directories(env: &Env) -> Result<Value> {
+    let report = directory::crawls(env).await?;
+    Ok(json!({ "latest": report.latest, "directories": report.directories }))
+}
+
+/// Daily subscriber estimates, most recent first
+///
+/// # Errors
+///
+/// * the estimates can't be read from the `Subscribers` Durable Object
+pub async fn subscribers(env: &Env, url: &Url) -> Result<Value> {
+    Ok(json!({ "days": subscribers::estimates(env, limit(url)).await? }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_authorized() {
+        assert!(authorized(Some("Bearer secret"), Some("secret")));
+        assert!(!authorized(Some("Bearer secreT"), Some("secret")));
+        assert!(!authorized(Some("Bearer secret2"), Some("secret")));
+        assert!(!authorized(Some("secret"), Some("secret")));
+        assert!(!authorized(None, Some("secret")));
+        // Admin endpoints are disabled without a token
+        assert!(!authorized(Some("Bearer secret"), None));
+        assert!(!authorized(Some("Bearer "), Some("")));
+    }
+
+    #[test]
+    fn test_limit() {
+        let url = |url: &str| Url::parse(url).unwrap();
+        assert_eq!(limit(&url("https://example.org/admin")), DEFAULT_LIMIT);
+        assert_eq!(limit(&url("https://example.org/admin?limit=5")), 5);
+        assert_eq!(
+            limit(&url("https://example.org/admin?limit=lol")),
+            DEFAULT_LIMIT
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let entries = vec![Entry {
+            user_agent: "MyCast/".to_string(),
+            count: 2,
+            example: "MyCast/2.4".to_string(),
+        }];
+        assert_eq!(
+            report(&Url::parse("https://example.org/admin").unwrap(), &entries),
+            json!({
+                "user_agents": [
+                    { "user_agent": "MyCast/", "count": 2, "example": "MyCast/2.4" }
+                ]
+            })
+        );
+        assert_eq!(
+            report(
+                &Url::parse("https://example.org/admin?format=opawg").unwrap(),
+                &entries
+            ),
+            json!([entries[0].opawg()])
+        );
+    }
+}
@@ -1,27 +1,200 @@
-use once_cell::sync::Lazy;
-use std::collections::HashMap;
+use crate::subscribers;
+use aho_corasick::AhoCorasick;
+use lru::LruCache;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroUsize;
+use std::sync::{LazyLock, Mutex};
 use worker::{Error, Request, Result};
 
+/// Number of user agents whose clients are cached
+const CACHE_SIZE: usize = 256;
+
+/// Name of clients which are not in the lookup table
+const UNKNOWN: &str = "Unknown Podcast Client";
+
+/// Client hints we ask browsers for with `Accept-CH`. Browsers reduce the
+/// information in their user agent strings, so web players can only be told
+/// apart by these.
+pub const ACCEPT_CH: &str = "Sec-CH-UA, Sec-CH-UA-Platform, Sec-CH-UA-Mobile, Sec-CH-UA-Model";
+
+/// Information from the User-Agent Client Hints of browsers
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Default)]
+pub struct Hints {
+    /// Browser brand from `Sec-CH-UA`, e.g. `Google Chrome`
+    pub brand: Option<String>,
+    /// Operating system from `Sec-CH-UA-Platform`, e.g. `Android`
+    pub platform: Option<String>,
+    /// Device model from `Sec-CH-UA-Model`, e.g. `Pixel 3`
+    pub model: Option<String>,
+    /// Whether the browser is on a mobile device, from `Sec-CH-UA-Mobile`
+    pub mobile: Option<bool>,
+}
+
+/// Parse a structured header string, e.g. `"Pixel 3"`. Empty strings are
+/// `None`.
+fn sf_string(value: &str) -> Option<String> {
+    let value = value.trim();
+    let value = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+        .replace("\\\"", "\"")
+        .replace("\\\\", "\\");
+    (!value.is_empty()).then_some(value)
+}
+
+/// Brands of a `Sec-CH-UA` header in order, e.g.
+/// `"Chromium";v="118", "Google Chrome";v="118"`
+fn brands(value: &str) -> Vec<String> {
+    let mut brands = vec![];
+    let mut current = String::new();
+    // Inside a quoted string, after a backslash, in the parameters of a brand
+    let (mut quoted, mut escaped, mut params) = (false, false, false);
+    for c in value.chars().chain(std::iter::once(',')) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            // Only the brand name, not the version parameter
+            ';' if !quoted => params = true,
+            ',' if !quoted => {
+                brands.extend(sf_string(&current));
+                current.clear();
+                params = false;
+                continue;
+            }
+            _ => {}
+        }
+        if !params {
+            current.push(c);
+        }
+    }
+    brands
+}
+
+/// Pick the most specific brand. GREASE brands like `Not=A?Brand` are
+/// ignored and `Chromium` is only used if there is nothing else.
+fn brand(value: &str) -> Option<String> {
+    brands(value)
+        .into_iter()
+        .filter(|brand| !(brand.starts_with("Not") && brand.contains("Brand")))
+        .min_by_key(|brand| brand == "Chromium")
+}
+
+impl Hints {
+    /// Read the client hints from the request headers. `header` returns the
+    /// value of the request header with the given name.
+    pub fn parse<F>(header: F) -> Self
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        Self {
+            brand: header("sec-ch-ua").as_deref().and_then(brand),
+            platform: header("sec-ch-ua-platform").as_deref().and_then(sf_string),
+            model: header("sec-ch-ua-model").as_deref().and_then(sf_string),
+            mobile: header("sec-ch-ua-mobile").and_then(|mobile| match mobile.trim() {
+                "?1" => Some(true),
+                "?0" => Some(false),
+                _ => None,
+            }),
+        }
+    }
+
+    /// Read the client hints from the request
+    fn from_request(request: &Request) -> Self {
+        Self::parse(|name| request.headers().get(name).ok().flatten())
+    }
+}
+
+/// Kind of client, used for market share reporting
+#[derive(
+    PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy, Hash, Serialize, Deserialize, JsonSchema,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum ClientCategory {
+    /// Podcast app on a phone, desktop or TV
+    PodcastApp,
+    /// Web player running in a browser
+    WebBrowser,
+    /// Crawler of a podcast directory, e.g. Apple Podcasts or Podchaser
+    DirectoryCrawler,
+    /// Feed reader or other aggregator of feeds
+    Aggregator,
+    /// Smart speaker or other voice assistant
+    SmartSpeaker,
+    /// Script or HTTP library
+    Script,
+    /// Search engine or SEO crawler
+    SeoBot,
+    /// Client which is not in the lookup table
+    Unknown,
+}
+
+impl ClientCategory {
+    /// Return whether clients of this category fetch without a listener
+    #[must_use]
+    pub const fn is_bot(self) -> bool {
+        matches!(
+            self,
+            Self::DirectoryCrawler | Self::Aggregator | Self::Script | Self::SeoBot
+        )
+    }
+}
+
 /// Podcast Client information
-#[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone)]
 pub struct Client {
     /// Name of the Podcast client
     name: String,
-    /// Whether the Podcast client is a bot
-    bot: bool,
+    /// Category of the Podcast client
+    category: ClientCategory,
+    /// Client hints of browser-based clients
+    hints: Hints,
+    /// Number of subscribers reported by feed readers in their user agent
+    subscribers: Option<u64>,
 }
 
 impl Client {
-    /// Create a new `Client` from a name and optionally set a bot flag
-    fn new(name: &str) -> Self {
-        // At some point we might move bot detection to the user agent lookup
-        // table or alternatively do it with post-processing
-        // For now we just check if the name contains "bot"
-        let bot = name.to_lowercase().contains("bot");
+    /// Create a new `Client` from a name and its category
+    pub(crate) fn new(name: &str, category: ClientCategory) -> Self {
         Self {
             name: name.to_string(),
-            bot,
+            category,
+            hints: Hints::default(),
+            subscribers: None,
+        }
+    }
+
+    /// Create a `Client` for a user agent which is not in the lookup table
+    fn unknown() -> Self {
+        Self::new(UNKNOWN, ClientCategory::Unknown)
+    }
+
+    /// Attach client hints. Clients which are unknown by their user agent
+    /// get named after their browser brand.
+    #[must_use]
+    pub fn with_hints(mut self, hints: Hints) -> Self {
+        if self.is_unknown() {
+            if let Some(brand) = &hints.brand {
+                self.name.clone_from(brand);
+                self.category = ClientCategory::WebBrowser;
+            }
         }
+        self.hints = hints;
+        self
+    }
+
+    /// Attach the number of subscribers a feed reader reported
+    #[must_use]
+    pub const fn with_subscribers(mut self, subscribers: Option<u64>) -> Self {
+        self.subscribers = subscribers;
+        self
+    }
+
+    /// Return the client hints of the client
+    pub const fn hints(&self) -> &Hints {
+        &self.hints
     }
 
     /// Return the name of the client
@@ -29,322 +202,540 @@ impl Client {
         &self.name
     }
 
+    /// Return whether the client is not in the lookup table
+    pub fn is_unknown(&self) -> bool {
+        self.category == ClientCategory::Unknown
+    }
+
+    /// Return the category of the client
+    pub const fn category(&self) -> ClientCategory {
+        self.category
+    }
+
+    /// Return the number of subscribers reported by a feed reader
+    pub const fn subscribers(&self) -> Option<u64> {
+        self.subscribers
+    }
+
     /// Return whether the client is a bot
     pub const fn is_bot(&self) -> bool {
-        self.bot
+        self.category.is_bot()
     }
 }
 
 /// Lookup table of user agents and the corresponding Podcast clients
 /// Source: <https://github.com/opawg/podcast-rss-useragents/blob/master/src/rss-ua.json>
-/// Each config consists of a pattern and a sanitized client name.
-static USER_AGENTS: Lazy<HashMap<String, String>> = Lazy::new(|| {
-    [
-        ("Acast", "Acast"),
-        ("Aggregator/", "Aggregator"),
-        ("AhrefsBot", "AhrefsSiteAudit [bot]"),
-        ("AirPodcasts/", "AirPodcasts-unknown"),
-        ("Airr Podcatcher", "Airr"),
-        ("Amazon Music Podcast", "Amazon Music Podcasts"),
-        ("AmazonMusic/", "Amazon Music Podcasts"),
-        ("AntennaPod/", "AntennaPod"),
-        ("anytime_podcast_player", "Anytime podcast player"),
-        ("iTunes/", "Apple iTunes"),
-        ("itunes", "Apple iTunes Store"),
-        ("iTMS", "Apple Podcasts - directory"),
-        ("Pocket Casts", "Pocket Casts"),
-        ("Podcasts/", "Apple Podcasts - via app"),
-        ("AppleCoreMedia/", "Apple Podcasts - via app"),
-        ("Balados/", "Apple Podcasts - via app"),
-        ("Balados/", "Apple Podcasts - via app"),
-        ("Podcasti/", "Apple Podcasts - via app"),
-        ("Podcastit/", "Apple Podcasts - via app"),
-        ("Podcasturi/", "Apple Podcasts - via app"),
-        ("Podcasty/", "Apple Podcasts - via app"),
-        ("Podcast’ler/", "Apple Podcasts - via app"),
-        ("Podkaster/", "Apple Podcasts - via app"),
-        ("PodkiteCrawler/", "Podkite"),
-        ("Podcaster/", "Apple Podcasts - via app"),
-        ("Podcast/", "Apple Podcasts - via app"),
-        ("Podcastok/", "Apple Podcasts - via app"),
-        ("Подкасти/", "Apple Podcasts - via app"),
-        ("Подкасты/", "Apple Podcasts - via app"),
-        ("פודקאסטים/", "Apple Podcasts - via app"),
-        ("البودكاست/", "Apple Podcasts - via app"),
-        ("पॉडकास्ट/", "Apple Podcasts - via app"),
-        ("พ็อดคาสท์/", "Apple Podcasts - via app"),
-        ("播客/", "Apple Podcasts - via app"),
-        ("팟캐스트/", "Apple Podcasts - via app"),
-        ("special_archiver", "archive.org"),
-        ("Audacy-Podcast-Scraper", "Audacy"),
-        ("audius", "Audius"),
-        ("AvailableOnBot", "AvailableOn"),
-        ("BazQux/", "BazQux Reader"),
-        ("BeyondPod", "BeyondPod"),
-        ("bingbot/", "BingBot"),
-        ("Bitcast/", "Bitcast"),
-        ("bitcastbot", "Bitcast"),
-        ("Blogtrottr/", "Blogtrottr"),
-        ("RawVoice Generator/", "Blubrry Podcasting"),
-        ("Breaker/", "Breaker"),
-        ("anytime.amugofjava.me.uk", "Breez"),
-        ("briefings.fm", "briefings.fm"),
-        ("Bullhorn Server", "Bullhorn"),
-        ("Castamatic/", "Castamatic"),
-        ("CastboxFeedParser", "Castbox"),
-        ("CastBox", "Castbox"),
-        ("CastFeedValidator", "CastFeedValidator"),
-        ("Tentacles", "Castro"),
+/// Each config consists of a pattern, a sanitized client name and the
+/// category of the client.
+/// If several patterns match, the longest one wins. Between patterns of the
+/// same length, the first one in the table wins.
+const USER_AGENTS: &[(&str, &str, ClientCategory)] = {
+    use ClientCategory::{Aggregator, DirectoryCrawler, PodcastApp, Script, SeoBot, SmartSpeaker};
+    &[
+        ("Acast", "Acast", PodcastApp),
+        ("Aggregator/", "Aggregator", Aggregator),
+        ("AhrefsBot", "AhrefsSiteAudit", SeoBot),
+        ("AirPodcasts/", "AirPodcasts-unknown", PodcastApp),
+        ("AlexaMediaPlayer/", "Amazon Alexa", SmartSpeaker),
+        ("Airr Podcatcher", "Airr", PodcastApp),
+        ("Amazon Music Podcast", "Amazon Music Podcasts", PodcastApp),
+        ("AmazonMusic/", "Amazon Music Podcasts", PodcastApp),
+        ("AntennaPod/", "AntennaPod", PodcastApp),
+        (
+            "anytime_podcast_player",
+            "Anytime podcast player",
+            PodcastApp,
+        ),
+        ("iTunes/", "Apple iTunes", PodcastApp),
+        ("itunes", "Apple iTunes Store", DirectoryCrawler),
+        ("iTMS", "Apple Podcasts - directory", DirectoryCrawler),
+        ("Pocket Casts", "Pocket Casts", PodcastApp),
+        ("Podcasts/", "Apple Podcasts - via app", PodcastApp),
+        ("AppleCoreMedia/", "Apple Podcasts - via app", PodcastApp),
+        ("Balados/", "Apple Podcasts - via app", PodcastApp),
+        ("Balados/", "Apple Podcasts - via app", PodcastApp),
+        ("Podcasti/", "Apple Podcasts - via app", PodcastApp),
+        ("Podcastit/", "Apple Podcasts - via app", PodcastApp),
+        ("Podcasturi/", "Apple Podcasts - via app", PodcastApp),
+        ("Podcasty/", "Apple Podcasts - via app", PodcastApp),
+        ("Podcast’ler/", "Apple Podcasts - via app", PodcastApp),
+        ("Podkaster/", "Apple Podcasts - via app", PodcastApp),
+        ("PodkiteCrawler/", "Podkite", DirectoryCrawler),
+        ("Podcaster/", "Apple Podcasts - via app", PodcastApp),
+        ("Podcast/", "Apple Podcasts - via app", PodcastApp),
+        ("Podcastok/", "Apple Podcasts - via app", PodcastApp),
+        ("Подкасти/", "Apple Podcasts - via app", PodcastApp),
+        ("Подкасты/", "Apple Podcasts - via app", PodcastApp),
+        ("פודקאסטים/", "Apple Podcasts - via app", PodcastApp),
+        ("البودكاست/", "Apple Podcasts - via app", PodcastApp),
+        ("पॉडकास्ट/", "Apple Podcasts - via app", PodcastApp),
+        ("พ็อดคาสท์/", "Apple Podcasts - via app", PodcastApp),
+        ("播客/", "Apple Podcasts - via app", PodcastApp),
+        ("팟캐스트/", "Apple Podcasts - via app", PodcastApp),
+        ("special_archiver", "archive.org", DirectoryCrawler),
+        ("Audacy-Podcast-Scraper", "Audacy", DirectoryCrawler),
+        ("audius", "Audius", PodcastApp),
+        ("AvailableOnBot", "AvailableOn", DirectoryCrawler),
+        ("BazQux/", "BazQux Reader", Aggregator),
+        ("BeyondPod", "BeyondPod", PodcastApp),
+        ("bingbot/", "BingBot", SeoBot),
+        ("Bitcast/", "Bitcast", DirectoryCrawler),
+        ("bitcastbot", "Bitcast", DirectoryCrawler),
+        ("Blogtrottr/", "Blogtrottr", Aggregator),
+        (
+            "RawVoice Generator/",
+            "Blubrry Podcasting",
+            DirectoryCrawler,
+        ),
+        ("Breaker/", "Breaker", PodcastApp),
+        ("anytime.amugofjava.me.uk", "Breez", PodcastApp),
+        ("briefings.fm", "briefings.fm", Aggregator),
+        ("Bullhorn Server", "Bullhorn", PodcastApp),
+        ("Castamatic/", "Castamatic", PodcastApp),
+        ("CastboxFeedParser", "Castbox", DirectoryCrawler),
+        ("CastBox", "Castbox", DirectoryCrawler),
+        ("CastFeedValidator", "CastFeedValidator", Script),
+        ("Tentacles", "Castro", PodcastApp),
         (
             "Mozilla/5.0 +https://chartable.com/crawler Trackable/",
             "Chartable",
+            DirectoryCrawler,
         ),
-        ("Podcast-CriticalMention/", "Critical Mention"),
-        ("CurioCaster/", "CurioCaster"),
-        ("DataForSeoBot", "DataForSEO"),
-        ("Deezer Podcasters/", "Deezer"),
-        ("DEVONthink", "DEVONthink"),
-        ("dlvr.it/", "dlvr.it"),
-        ("DoggCatcher", "DoggCatcher"),
-        ("Downcast/", "Downcast"),
-        ("edgar", "Edgar"),
-        ("Entale bot", "Entale"),
-        ("facebookexternalhit/", "Facebook"),
-        ("podcastbot", "Facebook Podcasts"),
-        ("Feed Wrangler/", "Feed Wrangler"),
-        ("Feedbin", "Feedbin"),
-        ("feeder.co", "Feeder"),
-        ("Feeder /", "Feeder"),
-        ("Feedly", "Feedly"),
-        ("Feedspot/", "Feedspot"),
-        ("ffydpoll", "Ffyd"),
-        ("FreshRSS", "FreshRSS"),
-        ("Fusebox", "Fusebox"),
-        ("FYEO/", "FYEO"),
-        ("fyyd/", "Fyyd"),
-        ("fyyd-poll", "Fyyd"),
-        ("Goodpods", "Goodpods"),
-        ("FeedFetcher-Google", "Google Feedfetcher"),
-        ("Googlebot", "Google Podcasts and Search [bot]"),
-        ("GEfektBot/1", "Govoren Efekt Bot"),
-        ("gPodder/", "gPodder"),
-        ("GSA/", "Google Podcasts Android"),
-        ("GooglePodcasts/", "Google Podcasts iOS"),
-        ("Google-Podcast", "Google Play Music Podcasts"),
-        ("hackney/", "Hackney-unknown"),
-        ("Headliner", "Headliner"),
-        ("Hypefactors", "Hypefactors"),
-        ("Buck/", "Hypefactors"),
-        ("iCatcher", "iCatcher! Podcast Player"),
+        ("Podcast-CriticalMention/", "Critical Mention", Aggregator),
+        ("CurioCaster/", "CurioCaster", PodcastApp),
+        ("DataForSeoBot", "DataForSEO", SeoBot),
+        ("Deezer Podcasters/", "Deezer", DirectoryCrawler),
+        ("DEVONthink", "DEVONthink", Aggregator),
+        ("dlvr.it/", "dlvr.it", Aggregator),
+        ("DoggCatcher", "DoggCatcher", PodcastApp),
+        ("Downcast/", "Downcast", PodcastApp),
+        ("edgar", "Edgar", Aggregator),
+        ("Entale bot", "Entale", DirectoryCrawler),
+        ("facebookexternalhit/", "Facebook", Aggregator),
+        ("podcastbot", "Facebook Podcasts", DirectoryCrawler),
+        ("Feed Wrangler/", "Feed Wrangler", Aggregator),
+        ("Feedbin", "Feedbin", Aggregator),
+        ("feeder.co", "Feeder", Aggregator),
+        ("Feeder /", "Feeder", Aggregator),
+        ("Feedly", "Feedly", Aggregator),
+        ("Feedspot/", "Feedspot", Aggregator),
+        ("ffydpoll", "Ffyd", DirectoryCrawler),
+        ("FreshRSS", "FreshRSS", Aggregator),
+        ("Fusebox", "Fusebox", PodcastApp),
+        ("FYEO/", "FYEO", PodcastApp),
+        ("fyyd/", "Fyyd", DirectoryCrawler),
+        ("fyyd-poll", "Fyyd", DirectoryCrawler),
+        ("Goodpods", "Goodpods", DirectoryCrawler),
+        ("FeedFetcher-Google", "Google Feedfetcher", Aggregator),
+        ("Googlebot", "Google Podcasts and Search", DirectoryCrawler),
+        ("GEfektBot/1", "Govoren Efekt Bot", SeoBot),
+        ("gPodder/", "gPodder", PodcastApp),
+        ("GSA/", "Google Podcasts Android", PodcastApp),
+        ("GooglePodcasts/", "Google Podcasts iOS", PodcastApp),
+        ("Google-Podcast", "Google Play Music Podcasts", PodcastApp),
+        ("hackney/", "Hackney-unknown", Script),
+        ("Headliner", "Headliner", Script),
+        ("Hypefactors", "Hypefactors", Aggregator),
+        ("Buck/", "Hypefactors", Aggregator),
+        ("iCatcher", "iCatcher! Podcast Player", PodcastApp),
         (
             "Mozilla/5.0 (Linux;) AppleWebKit/ Chrome/ Safari",
             "iHeartRadio",
+            DirectoryCrawler,
+        ),
+        ("inoreader.com", "Inoreader", Aggregator),
+        ("Instacast/", "Instacast", PodcastApp),
+        ("iVoox", "iVoox", DirectoryCrawler),
+        ("Krzana bot", "Krzana bot", Aggregator),
+        ("Leaf/", "Leaf-unknown", PodcastApp),
+        (
+            "life-radio-konsole-app",
+            "Life Radio Konsole App",
+            PodcastApp,
+        ),
+        ("Liferea/", "Liferea", Aggregator),
+        ("Lisnybot", "Lisny", DirectoryCrawler),
+        ("ListenAppBot", "Listen App", PodcastApp),
+        ("ListenNotes", "Listen Notes", DirectoryCrawler),
+        ("Luminary/", "Luminary", PodcastApp),
+        ("Micro.blog/", "Micro.blog", Aggregator),
+        ("MissinglettrBot/", "MissingLettr", Aggregator),
+        ("MixerBox Podcast Crawler", "MixerBox", DirectoryCrawler),
+        ("MuckRackFeedParser", "Muck Rack", Aggregator),
+        ("mypodapp.net", "My Pod", PodcastApp),
+        ("NetNewsWire", "NetNewsWire", Aggregator),
+        ("Netvibes", "Netvibes", Aggregator),
+        ("News Explorer/", "News Explorer", Aggregator),
+        ("NewsBlur Feed Fetcher", "NewsBlur", Aggregator),
+        ("Newsify Feed Fetcher", "Newsify", Aggregator),
+        ("NewsNow/", "NewsNow", Aggregator),
+        ("NextCloud-News/", "Nextcloud", Aggregator),
+        ("NRCAudioBot/", "NRC Audio", PodcastApp),
+        ("Office 365 Connectors", "Office 365", Aggregator),
+        ("Overcast/", "Overcast", PodcastApp),
+        ("OwlTail/", "OwlTail", DirectoryCrawler),
+        ("PandoraRSSCrawler", "Pandora", DirectoryCrawler),
+        ("PaperLiBot/", "Paper.li", Aggregator),
+        ("PetalBot", "PetalBot", SeoBot),
+        ("Playapod/", "Playapod", PodcastApp),
+        ("PlayerFM/1.0 Podcast Sync", "Player FM", DirectoryCrawler),
+        ("Plex/", "Plex", PodcastApp),
+        ("plex", "Plex", PodcastApp),
+        ("Plex Media Providers", "Plex", PodcastApp),
+        ("PocketCasts/", "Pocket Casts", PodcastApp),
+        ("Swoot/", "Pod Hero", PodcastApp),
+        ("Mozilla/5.0 (compatible; Podalong/", "Podalong", PodcastApp),
+        ("Podbay/", "Podbay", DirectoryCrawler),
+        ("PodbeanFeedReader/", "Podbean", DirectoryCrawler),
+        ("Podbean/", "Podbean", DirectoryCrawler),
+        ("PodcastGuru", "Podcast Guru", PodcastApp),
+        ("Podcastindex.org/", "Podcast Index", DirectoryCrawler),
+        ("PodcastRepublic/", "Podcast Republic", PodcastApp),
+        ("PodcastAddict/", "PodcastAddict", PodcastApp),
+        ("Podcastly/", "Podcastly", PodcastApp),
+        ("Podcastly/", "Podcastly", PodcastApp),
+        ("PodcastScraper", "PodcastScraper", Script),
+        ("Podchaser-Parser", "Podchaser", DirectoryCrawler),
+        ("Podchaser", "Podchaser", DirectoryCrawler),
+        ("podCloud/", "podCloud", DirectoryCrawler),
+        ("PodCruncher", "PodCruncher", PodcastApp),
+        ("PodEngine/", "PodEngine", DirectoryCrawler),
+        ("podfollowbot/", "Podfollow", DirectoryCrawler),
+        ("podfriend", "Podfriend", PodcastApp),
+        ("PodheroBot/", "Podhero", PodcastApp),
+        ("PodHound/", "PodHound", PodcastApp),
+        ("Podimo/", "Podimo", PodcastApp),
+        ("Podinstall", "Podinstall", PodcastApp),
+        ("Podkicker", "Podkicker", PodcastApp),
+        ("PodLink", "PodLink", DirectoryCrawler),
+        ("PodBotLP/", "PodLP", PodcastApp),
+        ("PodMN/", "PodMN", DirectoryCrawler),
+        ("PodMust/", "PodMust", DirectoryCrawler),
+        ("Podmust/", "Podmust", DirectoryCrawler),
+        ("PodnewsBot", "PodnewsBot", DirectoryCrawler),
+        ("PodParadise", "PodParadise", DirectoryCrawler),
+        ("Podplay-Podcast-Sync/", "Podplay", PodcastApp),
+        ("Podsights/", "Podsights", DirectoryCrawler),
+        ("Podtail/", "Podtail", DirectoryCrawler),
+        (
+            "Mozilla/5.0 (compatible; Podtail/",
+            "Podtail",
+            DirectoryCrawler,
         ),
-        ("inoreader.com", "Inoreader"),
-        ("Instacast/", "Instacast"),
-        ("iVoox", "iVoox"),
-        ("Krzana bot", "Krzana bot"),
-        ("Leaf/", "Leaf-unknown"),
-        ("life-radio-konsole-app", "Life Radio Konsole App"),
-        ("Liferea/", "Liferea"),
-        ("Lisnybot", "Lisny"),
-        ("ListenAppBot", "Listen App"),
-        ("ListenNotes", "Listen Notes"),
-        ("Luminary/", "Luminary"),
-        ("Micro.blog/", "Micro.blog"),
-        ("MissinglettrBot/", "MissingLettr"),
-        ("MixerBox Podcast Crawler", "MixerBox"),
-        ("MuckRackFeedParser", "Muck Rack"),
-        ("mypodapp.net", "My Pod"),
-        ("NetNewsWire", "NetNewsWire"),
-        ("Netvibes", "Netvibes"),
-        ("News Explorer/", "News Explorer"),
-        ("NewsBlur Feed Fetcher", "NewsBlur"),
-        ("Newsify Feed Fetcher", "Newsify"),
-        ("NewsNow/", "NewsNow"),
-        ("NextCloud-News/", "Nextcloud"),
-        ("NRCAudioBot/", "NRC Audio"),
-        ("Office 365 Connectors", "Office 365"),
-        ("Overcast/", "Overcast"),
-        ("OwlTail/", "OwlTail"),
-        ("PandoraRSSCrawler", "Pandora"),
-        ("PaperLiBot/", "Paper.li"),
-        ("PetalBot", "PetalBot"),
-        ("Playapod/", "Playapod"),
-        ("PlayerFM/1.0 Podcast Sync", "Player FM"),
-        ("Plex/", "Plex"),
-        ("plex", "Plex"),
-        ("Plex Media Providers", "Plex"),
-        ("PocketCasts/", "Pocket Casts"),
-        ("Swoot/", "Pod Hero"),
-        ("Mozilla/5.0 (compatible; Podalong/", "Podalong"),
-        ("Podbay/", "Podbay"),
-        ("PodbeanFeedReader/", "Podbean"),
-        ("Podbean/", "Podbean"),
-        ("PodcastGuru", "Podcast Guru"),
-        ("Podcastindex.org/", "Podcast Index"),
-        ("PodcastRepublic/", "Podcast Republic"),
-        ("PodcastAddict/", "PodcastAddict"),
-        ("Podcastly/", "Podcastly"),
-        ("Podcastly/", "Podcastly"),
-        ("PodcastScraper", "PodcastScraper"),
-        ("Podchaser-Parser", "Podchaser"),
-        ("Podchaser", "Podchaser"),
-        ("podCloud/", "podCloud"),
-        ("PodCruncher", "PodCruncher"),
-        ("PodEngine/", "PodEngine"),
-        ("podfollowbot/", "Podfollow"),
-        ("podfriend", "Podfriend"),
-        ("PodheroBot/", "Podhero"),
-        ("PodHound/", "PodHound"),
-        ("Podimo/", "Podimo"),
-        ("Podinstall", "Podinstall"),
-        ("Podkicker", "Podkicker"),
-        ("PodLink", "PodLink"),
-        ("PodBotLP/", "PodLP"),
-        ("PodMN/", "PodMN"),
-        ("PodMust/", "PodMust"),
-        ("Podmust/", "Podmust"),
-        ("PodnewsBot", "PodnewsBot"),
-        ("PodParadise", "PodParadise"),
-        ("Podplay-Podcast-Sync/", "Podplay"),
-        ("Podsights/", "Podsights"),
-        ("Podtail/", "Podtail"),
-        ("Mozilla/5.0 (compatible; Podtail/", "Podtail"),
-        ("podtail", "Podtail"),
-        ("Podtrac Feed Scanner", "Podtrac"),
-        ("Podverse/Feed Parser", "Podverse"),
-        ("Podyssey App", "Podyssey App"),
-        ("Radical-Edward", "Radical-Edward Podcast Discovery"),
-        ("axios/0.19.1", "radio.com"),
-        ("RadioCut/", "Radiocut"),
-        ("radiofeed/", "Radiofeed"),
-        ("Radioline", "Radioline"),
-        ("RadioPublic-Web/", "RadioPublic"),
-        ("reason/", "Reason"),
-        ("RedCircle", "RedCircle"),
-        ("Reedah/1", "Reedah"),
-        ("Reeder/", "Reeder"),
-        ("Repod/", "Repod"),
-        ("Rephonic/", "Rephonic"),
-        ("rssapi.net", "RSS API"),
-        ("RSSOwl/", "RSSOwl"),
-        ("RSSRadio", "RSSRadio"),
-        ("R6_FeedFetcher", "Salesforce"),
-        ("sp-agent", "Samsung Podcasts"),
-        ("semantic-visions.com", "Semantic Visions"),
-        ("SemrushBot", "SEMrushBot"),
-        ("SEOkicks", "SEOkicks"),
-        ("SerendeputyBot/", "Serendeputy"),
-        ("Shadow", "Shadow"),
-        ("SismicsReaderBot", "Sismics Reader"),
-        ("Slackbot", "Slackbot"),
-        ("SocialBeeAgent", "SocialBeeAgent"),
-        ("Sonnet/", "Sonnet"),
-        ("Spotify/", "Spotify"),
-        ("Spreaker/", "Spreaker"),
-        ("StitcherBot", "Stitcher"),
-        ("Subcast/", "Subcast-unknown"),
-        ("Superfeedr bot", "Superfeedr"),
-        ("taddy.org/", "taddy"),
-        ("TapTapes", "Taptapes"),
-        ("theoldreader.com", "The Old Reader"),
-        ("tweetedtimes.com", "The Tweeted Times"),
-        ("Expanse, a Palo Alto Networks company", "Expanse [bot]"),
-        ("Tiny Tiny RSS", "Tiny Tiny RSS"),
-        ("TPA/", "TPA-unknown"),
-        ("trendictionbot", "Trendiction Bot"),
-        ("Tumult", "Tumult"),
-        ("TuneInRssParser/", "TuneIn"),
-        ("um-IC/", "Ubermetrics"),
-        ("verbbot/", "Verb.fm"),
-        ("VictorReader", "Victor Reader"),
-        ("Vienna/", "ViennaRSS"),
-        ("Vodacast", "Vodacast"),
-        ("VurblBot/", "Vurbl"),
-        ("Winds:", "Winds"),
-        ("russ(xiaoyuzhou)/1.0", "Xiao Yu Zhou"),
-        ("Russ", "Xiao Yu Zhou"),
-        ("YandexBot/", "YandexBot"),
-        ("Zapier", "Zapier"),
-        ("ZoominfoBot", "Zoominfo"),
-        // Scripts and libraries get categorized as bots
-        ("axios", "Script [bot]"),
-        ("Go-http-client/", "Script [bot]"),
-        ("node-fetch/", "Script [bot]"),
-        ("lychee/", "Script [bot]"),
-        ("python-requests/", "Script [bot]"),
-        ("Ruby", "Script [bot]"),
-        ("UniversalFeedParser/", "Script [bot]"),
+        ("podtail", "Podtail", DirectoryCrawler),
+        ("Podtrac Feed Scanner", "Podtrac", DirectoryCrawler),
+        ("Podverse/Feed Parser", "Podverse", PodcastApp),
+        ("Podyssey App", "Podyssey App", PodcastApp),
+        (
+            "Radical-Edward",
+            "Radical-Edward Podcast Discovery",
+            DirectoryCrawler,
+        ),
+        ("axios/0.19.1", "radio.com", DirectoryCrawler),
+        ("RadioCut/", "Radiocut", PodcastApp),
+        ("radiofeed/", "Radiofeed", Aggregator),
+        ("Radioline", "Radioline", PodcastApp),
+        ("RadioPublic-Web/", "RadioPublic", DirectoryCrawler),
+        ("reason/", "Reason", PodcastApp),
+        ("RedCircle", "RedCircle", DirectoryCrawler),
+        ("Reedah/1", "Reedah", Aggregator),
+        ("Reeder/", "Reeder", Aggregator),
+        ("Repod/", "Repod", PodcastApp),
+        ("Rephonic/", "Rephonic", DirectoryCrawler),
+        ("rssapi.net", "RSS API", Aggregator),
+        ("RSSOwl/", "RSSOwl", Aggregator),
+        ("RSSRadio", "RSSRadio", PodcastApp),
+        ("R6_FeedFetcher", "Salesforce", Aggregator),
+        ("sp-agent", "Samsung Podcasts", PodcastApp),
+        ("semantic-visions.com", "Semantic Visions", Aggregator),
+        ("SemrushBot", "SEMrushBot", SeoBot),
+        ("SEOkicks", "SEOkicks", SeoBot),
+        ("SerendeputyBot/", "Serendeputy", Aggregator),
+        ("Shadow", "Shadow", PodcastApp),
+        ("SismicsReaderBot", "Sismics Reader", Aggregator),
+        ("Slackbot", "Slackbot", Aggregator),
+        ("SocialBeeAgent", "SocialBeeAgent", Aggregator),
+        ("Sonnet/", "Sonnet", PodcastApp),
+        ("Sonos/", "Sonos", SmartSpeaker),
+        ("Spotify/", "Spotify", PodcastApp),
+        ("Spotify/1.0", "Spotify", DirectoryCrawler),
+        ("Spreaker/", "Spreaker", DirectoryCrawler),
+        ("StitcherBot", "Stitcher", DirectoryCrawler),
+        ("Subcast/", "Subcast-unknown", PodcastApp),
+        ("Superfeedr bot", "Superfeedr", Aggregator),
+        ("taddy.org/", "taddy", DirectoryCrawler),
+        ("TapTapes", "Taptapes", PodcastApp),
+        ("theoldreader.com", "The Old Reader", Aggregator),
+        ("tweetedtimes.com", "The Tweeted Times", Aggregator),
+        ("Expanse, a Palo Alto Networks company", "Expanse", SeoBot),
+        ("Tiny Tiny RSS", "Tiny Tiny RSS", Aggregator),
+        ("TPA/", "TPA-unknown", PodcastApp),
+        ("trendictionbot", "Trendiction Bot", SeoBot),
+        ("Tumult", "Tumult", PodcastApp),
+        ("TuneInRssParser/", "TuneIn", DirectoryCrawler),
+        ("um-IC/", "Ubermetrics", Aggregator),
+        ("verbbot/", "Verb.fm", DirectoryCrawler),
+        ("VictorReader", "Victor Reader", PodcastApp),
+        ("Vienna/", "ViennaRSS", Aggregator),
+        ("Vodacast", "Vodacast", PodcastApp),
+        ("VurblBot/", "Vurbl", DirectoryCrawler),
+        ("Winds:", "Winds", Aggregator),
+        ("russ(xiaoyuzhou)/1.0", "Xiao Yu Zhou", PodcastApp),
+        ("Russ", "Xiao Yu Zhou", PodcastApp),
+        ("YandexBot/", "YandexBot", SeoBot),
+        ("Zapier", "Zapier", Aggregator),
+        ("ZoominfoBot", "Zoominfo", SeoBot),
+        ("axios", "Script", Script),
+        ("Go-http-client/", "Script", Script),
+        ("node-fetch/", "Script", Script),
+        ("lychee/", "Script", Script),
+        ("python-requests/", "Script", Script),
+        ("Ruby", "Script", Script),
+        ("UniversalFeedParser/", "Script", Script),
     ]
-    .iter()
-    .map(|(k, v)| ((*k).to_string(), (*v).to_string()))
-    .collect()
+};
+
+/// Automaton matching all patterns of the lookup table at once
+static MATCHER: LazyLock<AhoCorasick> = LazyLock::new(|| {
+    AhoCorasick::new(USER_AGENTS.iter().map(|(pattern, _, _)| pattern))
+        .expect("user agent patterns are valid")
 });
 
+/// Clients of recently seen user agents
+static CACHE: LazyLock<Mutex<LruCache<String, Client>>> =
+    LazyLock::new(|| Mutex::new(LruCache::new(NonZeroUsize::new(CACHE_SIZE).unwrap())));
+
 /// Try to return a canonical user agent from the `user-agent` header
 pub fn from(request: &Request) -> Result<Client> {
     let ua_string = request.headers().get("user-agent")?;
-    let ua_string = match ua_string {
-        Some(ua) => ua,
-        None => {
-            return Err(Error::RustError(
-                "Cannot read user agent from request".to_owned(),
-            ))
-        }
+    let Some(ua_string) = ua_string else {
+        return Err(Error::RustError(
+            "Cannot read user agent from request".to_owned(),
+        ));
     };
-    lookup(&ua_string).ok_or_else(|| "Cannot read user agent".into())
+    Ok(cached_lookup(&ua_string)
+        .ok_or("Cannot read user agent")?
+        .with_hints(Hints::from_request(request)))
 }
 
 /// Lookup the given user agent string in the table of known user agents
 #[must_use]
 fn lookup(user_agent: &str) -> Option<Client> {
-    for (pattern, agent) in USER_AGENTS.iter() {
-        if user_agent.contains(pattern) {
-            return Some(Client::new(agent));
-        }
+    MATCHER
+        .find_overlapping_iter(user_agent)
+        .map(|m| m.pattern().as_usize())
+        .min_by_key(|&index| (std::cmp::Reverse(USER_AGENTS[index].0.len()), index))
+        .map(|index| {
+            let (_, name, category) = USER_AGENTS[index];
+            let client = Client::new(name, category);
+            // Feed readers fetch the feed once for all of their subscribers
+            if category == ClientCategory::Aggregator {
+                return client.with_subscribers(subscribers::count(user_agent));
+            }
+            client
+        })
+}
+
+/// Lookup the given user agent string, using the cache of recently seen
+/// user agents
+fn cached_lookup(user_agent: &str) -> Option<Client> {
+    if let Some(client) = CACHE.lock().ok()?.get(user_agent) {
+        return Some(client.clone());
+    }
+    let client = lookup(user_agent).unwrap_or_else(Client::unknown);
+    if let Ok(mut cache) = CACHE.lock() {
+        cache.put(user_agent.to_string(), client.clone());
     }
-    None
+    Some(client)
 }
 
 /// Get Podcast client from request user agent
+///
+/// This is not free, so it should be called once per request and the result
+/// passed around.
 pub fn client(request: &Request) -> Client {
-    from(request).unwrap_or_else(|_| Client::new("Unknown Podcast Client"))
+    from(request).unwrap_or_else(|_| Client::unknown().with_hints(Hints::from_request(request)))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_hints() {
+        let headers = [
+            (
+                "sec-ch-ua",
+                r#""Chromium";v="118", "Google Chrome";v="118", "Not=A?Brand";v="99""#,
+            ),
+            ("sec-ch-ua-platform", r#""Android""#),
+            ("sec-ch-ua-mobile", "?1"),
+            ("sec-ch-ua-model", r#""Pixel 3""#),
+        ];
+        let hints = Hints::parse(|name| {
+            headers
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| (*value).to_string())
+        });
+        assert_eq!(
+            hints,
+            Hints {
+                brand: Some("Google Chrome".to_string()),
+                platform: Some("Android".to_string()),
+                model: Some("Pixel 3".to_string()),
+                mobile: Some(true),
+            }
+        );
+        assert_eq!(Hints::parse(|_| None), Hints::default());
+    }
+
+    #[test]
+    fn test_brand() {
+        assert_eq!(
+            brand(r#""Not;A=Brand";v="8", "Chromium";v="118""#),
+            Some("Chromium".to_string())
+        );
+        assert_eq!(
+            brand(r#""Chromium";v="118", "Microsoft Edge";v="118""#),
+            Some("Microsoft Edge".to_string())
+        );
+        assert_eq!(brand(r#""Not A(Brand";v="99""#), None);
+        assert_eq!(brand(""), None);
+    }
+
+    #[test]
+    fn test_with_hints() {
+        let hints = Hints {
+            brand: Some("Google Chrome".to_string()),
+            ..Hints::default()
+        };
+        assert_eq!(
+            Client::unknown().with_hints(hints.clone()).name(),
+            "Google Chrome"
+        );
+        assert_eq!(
+            Client::unknown().with_hints(hints.clone()).category(),
+            ClientCategory::WebBrowser
+        );
+        assert_eq!(
+            Client::unknown().with_hints(Hints::default()).category(),
+            ClientCategory::Unknown
+        );
+        assert_eq!(
+            Client::new("Spotify", ClientCategory::PodcastApp)
+                .with_hints(hints.clone())
+                .name(),
+            "Spotify"
+        );
+        assert_eq!(
+            Client::new("Spotify", ClientCategory::PodcastApp)
+                .with_hints(hints.clone())
+                .hints(),
+            &hints
+        );
+    }
+
+    #[test]
+    fn test_category() {
+        let client = lookup("python-requests/2.28.1").unwrap();
+        assert_eq!(client.name(), "Script");
+        assert_eq!(client.category(), ClientCategory::Script);
+        assert!(client.is_bot());
+        let client = lookup("Overcast/3.0 (+http://overcast.fm/; iOS podcast app)").unwrap();
+        assert_eq!(client.category(), ClientCategory::PodcastApp);
+        assert!(!client.is_bot());
+        assert!(!Client::unknown().is_bot());
+        let client = lookup("AlexaMediaPlayer/2.1.4676.0 (Linux;Android 5.1.1)").unwrap();
+        assert_eq!(client.category(), ClientCategory::SmartSpeaker);
+        assert!(!client.is_bot());
+        assert_eq!(
+            serde_json::to_value(ClientCategory::SeoBot).unwrap(),
+            "seo-bot"
+        );
+    }
+
+    #[test]
+    fn test_subscribers() {
+        let client =
+            lookup("Feedly/1.0 (+http://www.feedly.com/fetcher.html; 42 subscribers)").unwrap();
+        assert_eq!(client.name(), "Feedly");
+        assert_eq!(client.subscribers(), Some(42));
+        // Only feed readers report subscribers
+        assert_eq!(
+            lookup("Overcast/3.0 (+http://overcast.fm/; 42 subscribers)")
+                .unwrap()
+                .subscribers(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_lookup_priority() {
+        // Longest pattern wins
+        assert_eq!(
+            lookup("russ(xiaoyuzhou)/1.0").unwrap(),
+            Client::new("Xiao Yu Zhou", ClientCategory::PodcastApp)
+        );
+        assert_eq!(
+            lookup("AirPodcasts/2.1").unwrap(),
+            Client::new("AirPodcasts-unknown", ClientCategory::PodcastApp)
+        );
+        // Position in the user agent doesn't matter
+        assert_eq!(
+            lookup("Mozilla/5.0 AntennaPod/2.7").unwrap(),
+            lookup("AntennaPod/2.7 Mozilla/5.0").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_cached_lookup() {
+        let user_agent = "Spotify/8.6.88.1104 Android/30 (SM-A525F)";
+        assert_eq!(
+            cached_lookup(user_agent),
+            Some(Client::new("Spotify", ClientCategory::PodcastApp))
+        );
+        assert!(CACHE.lock().unwrap().contains(user_agent));
+        assert_eq!(
+            cached_lookup(user_agent),
+            Some(Client::new("Spotify", ClientCategory::PodcastApp))
+        );
+        assert_eq!(cached_lookup("Something Random"), Some(Client::unknown()));
+    }
+
     #[test]
     fn test_lookup() {
         assert_eq!(
             lookup("Spotify/8.6.88.1104 Android/30 (SM-A525F)").unwrap(),
-            Client::new("Spotify")
+            Client::new("Spotify", ClientCategory::PodcastApp)
         );
         assert_eq!(
             lookup("Spotify/8.6.82 iOS/15.1 (iPhone12,1)").unwrap(),
-            Client::new("Spotify")
+            Client::new("Spotify", ClientCategory::PodcastApp)
+        );
+        assert_eq!(
+            lookup("Spotify/1.0").unwrap(),
+            Client::new("Spotify", ClientCategory::DirectoryCrawler)
         );
         assert_eq!(
             lookup("AmazonMusic/9.16.0 iPhone12,1 CFNetwork/1128.0.1 Darwin/19.6.0").unwrap(),
-            Client::new("Amazon Music Podcasts")
+            Client::new("Amazon Music Podcasts", ClientCategory::PodcastApp)
         );
         assert_eq!(lookup("Something Random"), None);
         assert_eq!(
             lookup("UA: Mozilla/5.0 (Linux; Android 10; Pixel 3a XL Build/QQ3A.200805.001; wv) AppleWebKit/537.36 (KHTML, like Gecko) Version/4.0 Chrome/86.0.4240.198 Mobile Safari/537.36 GSA/11.38.8.23.arm64").unwrap(),
-            Client::new("Google Podcasts Android")
+            Client::new("Google Podcasts Android", ClientCategory::PodcastApp)
         );
         assert_eq!(
             lookup("AppleCoreMedia/1.0.0.21G72 (Macintosh; U; Intel Mac OS X 12_5; en_us)")
                 .unwrap(),
-            Client::new("Apple Podcasts - via app")
+            Client::new("Apple Podcasts - via app", ClientCategory::PodcastApp)
         );
 
         assert_eq!(
             lookup("Expanse, a Palo Alto Networks company, searches across the global IPv4 space multiple times per day to identify customers&#39; presences on the Internet. If you would like to be excluded from our scans, please send IP addresses/domains to: scaninfo@paloaltonetworks.com")
                 .unwrap(),
-            Client::new("Expanse [bot]")
+            Client::new("Expanse", ClientCategory::SeoBot)
         );
     }
 }
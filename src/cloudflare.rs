@@ -0,0 +1,449 @@
+//! Request metadata provided by Cloudflare
+//!
+//! Cloudflare attaches information about the client to every request, e.g.
+//! its location, network and TLS connection. Which of these fields get
+//! captured in events can be configured per deployment, so privacy-sensitive
+//! fields like the postal code can be turned off. Fields without a value are
+//! omitted.
+
+use schemars::JsonSchema;
+use serde::Serialize;
+use worker::js_sys::Reflect;
+use worker::wasm_bindgen::JsValue;
+use worker::{Error, Request, Result};
+
+/// Names of all fields which can be captured
+pub const FIELDS: &[&str] = &[
+    "colo",
+    "asn",
+    "country",
+    "http_protocol",
+    "request_priority",
+    "tls_version",
+    "tls_cipher",
+    "tls_client_auth",
+    "city",
+    "continent",
+    "latitude",
+    "longitude",
+    "postal_code",
+    "metro_code",
+    "region",
+    "region_code",
+    "timezone",
+    "client_tcp_rtt",
+    "bot_score",
+];
+
+/// Selection of the fields which get captured
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Fields {
+    /// Captured fields, all fields if `None`
+    allow: Option<Vec<String>>,
+    /// Fields which are never captured
+    deny: Vec<String>,
+}
+
+/// Parse a comma-separated list of field names
+fn parse(fields: &str) -> Result<Vec<String>> {
+    fields
+        .split(',')
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+        .map(|field| {
+            if FIELDS.contains(&field) {
+                Ok(field.to_string())
+            } else {
+                Err(Error::RustError(format!(
+                    "Unknown Cloudflare field: {field}"
+                )))
+            }
+        })
+        .collect()
+}
+
+impl Fields {
+    /// Only capture the given comma-separated fields, e.g. `colo, country`
+    ///
+    /// # Errors
+    ///
+    /// * the list contains an unknown field
+    pub fn with_allow(mut self, fields: &str) -> Result<Self> {
+        self.allow = Some(parse(fields)?);
+        Ok(self)
+    }
+
+    /// Never capture the given comma-separated fields, e.g. `postal_code`
+    ///
+    /// # Errors
+    ///
+    /// * the list contains an unknown field
+    pub fn with_deny(mut self, fields: &str) -> Result<Self> {
+        self.deny = parse(fields)?;
+        Ok(self)
+    }
+
+    /// Check if the field with the given name gets captured
+    #[must_use]
+    pub fn includes(&self, field: &str) -> bool {
+        self.allow
+            .as_ref()
+            .is_none_or(|allow| allow.iter().any(|allowed| allowed == field))
+            && !self.deny.iter().any(|denied| denied == field)
+    }
+}
+
+/// HTTP/2 prioritization requested by the browser
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct RequestPriority {
+    /// Requested weight
+    pub weight: usize,
+    /// Requested exclusive flag
+    pub exclusive: bool,
+    /// Stream ID of the request group
+    pub group: usize,
+    /// Weight of the request group
+    pub group_weight: usize,
+}
+
+impl RequestPriority {
+    /// Parse the priority from Cloudflare's format, e.g.
+    /// `weight=192;exclusive=0;group=3;group-weight=127`. Unknown keys and
+    /// invalid values are skipped; `None` if nothing is known
+    fn parse(value: &str) -> Option<Self> {
+        let mut priority = Self {
+            weight: 1,
+            exclusive: false,
+            group: 0,
+            group_weight: 0,
+        };
+        let mut known = false;
+        for (key, value) in value.split(';').filter_map(|pair| pair.split_once('=')) {
+            let parsed = match key.trim() {
+                "weight" => value.parse().map(|weight| priority.weight = weight).is_ok(),
+                "exclusive" => {
+                    priority.exclusive = value == "1";
+                    true
+                }
+                "group" => value.parse().map(|group| priority.group = group).is_ok(),
+                "group-weight" => value
+                    .parse()
+                    .map(|weight| priority.group_weight = weight)
+                    .is_ok(),
+                _ => false,
+            };
+            known |= parsed;
+        }
+        known.then_some(priority)
+    }
+}
+
+/// Client certificate of mutual TLS, only set when using Cloudflare Access
+/// or API Shield. The field names follow Cloudflare's.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, JsonSchema)]
+#[allow(clippy::struct_field_names)]
+pub struct TlsClientAuth {
+    /// `1` if a certificate was presented
+    pub cert_presented: String,
+    /// Result of the verification, e.g. `SUCCESS`
+    pub cert_verified: String,
+    /// Issuer of the certificate
+    pub cert_issuer_dn: String,
+    /// Issuer of the certificate in RFC 2253 format
+    pub cert_issuer_dn_rfc2253: String,
+    /// Issuer of the certificate in legacy format
+    pub cert_issuer_dn_legacy: String,
+    /// Subject of the certificate
+    pub cert_subject_dn: String,
+    /// Subject of the certificate in RFC 2253 format
+    pub cert_subject_dn_rfc2253: String,
+    /// Subject of the certificate in legacy format
+    pub cert_subject_dn_legacy: String,
+    /// Serial number of the certificate
+    pub cert_serial: String,
+    /// SHA-1 fingerprint of the certificate
+    pub cert_fingerprint_sha1: String,
+    /// Start of the validity of the certificate
+    pub cert_not_before: String,
+    /// End of the validity of the certificate
+    pub cert_not_after: String,
+}
+
+/// Request metadata provided by Cloudflare. Fields which are not captured
+/// or have no value are omitted.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, JsonSchema)]
+pub struct Cloudflare {
+    /// IATA airport code of the data center which received the request,
+    /// e.g. `FRA`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub colo: Option<String>,
+    /// Autonomous system number of the client network
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asn: Option<u32>,
+    /// Country of the client as ISO 3166-1 alpha-2 code, e.g. `DE`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+    /// HTTP protocol of the request, e.g. `HTTP/2`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_protocol: Option<String>,
+    /// HTTP/2 prioritization requested by the browser
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_priority: Option<RequestPriority>,
+    /// TLS version of the connection, e.g. `TLSv1.3`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_version: Option<String>,
+    /// TLS cipher of the connection, e.g. `AEAD-AES128-GCM-SHA256`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_cipher: Option<String>,
+    /// Client certificate of mutual TLS
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls_client_auth: Option<TlsClientAuth>,
+    /// City of the client
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub city: Option<String>,
+    /// Continent of the client, e.g. `EU`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub continent: Option<String>,
+    /// Approximate latitude of the client
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latitude: Option<f32>,
+    /// Approximate longitude of the client
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub longitude: Option<f32>,
+    /// Postal code of the client, e.g. `10115`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub postal_code: Option<String>,
+    /// Metro code (DMA) of the client, only in the US
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metro_code: Option<String>,
+    /// Region of the client, e.g. `Bavaria`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    /// ISO 3166-2 code of the region, e.g. `BY`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region_code: Option<String>,
+    /// Timezone of the client, e.g. `Europe/Berlin`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    /// Round-trip time of the TCP connection to the client in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_tcp_rtt: Option<u32>,
+    /// Bot management score from 1 (bot) to 99 (human), only with Bot
+    /// Management enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bot_score: Option<u32>,
+}
+
+/// Read a property of the `cf` object of the request which is not exposed
+/// by `worker::Cf`, e.g. `["botManagement", "score"]`
+fn property(request: &Request, path: &[&str]) -> Option<JsValue> {
+    let mut value: JsValue = request.inner().cf().into();
+    for key in path {
+        if !value.is_object() {
+            return None;
+        }
+        value = Reflect::get(&value, &JsValue::from_str(key)).ok()?;
+    }
+    (!value.is_undefined() && !value.is_null()).then_some(value)
+}
+
+/// Read a numeric property of the `cf` object of the request
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn number(request: &Request, path: &[&str]) -> Option<u32> {
+    property(request, path)?
+        .as_f64()
+        .filter(|number| *number >= 0.0)
+        .map(|number| number as u32)
+}
+
+/// Drop empty strings, which Cloudflare sends for some unknown values
+fn non_empty(value: String) -> Option<String> {
+    (!value.is_empty()).then_some(value)
+}
+
+impl Cloudflare {
+    /// Capture the selected fields of the request
+    #[must_use]
+    pub fn capture(request: &Request, fields: &Fields) -> Self {
+        let cf = request.cf();
+        let coordinates = cf.coordinates();
+        let has = |field| fields.includes(field);
+        Self {
+            colo: has("colo").then(|| cf.colo()).and_then(non_empty),
+            asn: has("asn").then(|| cf.asn()),
+            country: has("country").then(|| cf.country()).flatten(),
+            http_protocol: has("http_protocol")
+                .then(|| cf.http_protocol())
+                .and_then(non_empty),
+            // `worker::Cf::request_priority` panics for empty values and
+            // unknown keys
+            request_priority: has("request_priority")
+                .then(|| property(request, &["requestPriority"])?.as_string())
+                .flatten()
+                .and_then(|priority| RequestPriority::parse(&priority)),
+            tls_version: has("tls_version")
+                .then(|| cf.tls_version())
+                .and_then(non_empty),
+            tls_cipher: has("tls_cipher")
+                .then(|| cf.tls_cipher())
+                .and_then(non_empty),
+            tls_client_auth: has("tls_client_auth")
+                .then(|| cf.tls_client_auth())
+                .flatten()
+                .map(|auth| TlsClientAuth {
+                    cert_presented: auth.cert_presented(),
+                    cert_verified: auth.cert_verified(),
+                    cert_issuer_dn: auth.cert_issuer_dn(),
+                    cert_issuer_dn_rfc2253: auth.cert_issuer_dn_rfc2253(),
+                    cert_issuer_dn_legacy: auth.cert_issuer_dn_legacy(),
+                    cert_subject_dn: auth.cert_subject_dn(),
+                    cert_subject_dn_rfc2253: auth.cert_subject_dn_rfc225(),
+                    cert_subject_dn_legacy: auth.cert_subject_dn_legacy(),
+                    cert_serial: auth.cert_serial(),
+                    cert_fingerprint_sha1: auth.cert_fingerprint_sha1(),
+                    cert_not_before: auth.cert_not_before(),
+                    cert_not_after: auth.cert_not_after(),
+                }),
+            city: has("city").then(|| cf.city()).flatten(),
+            continent: has("continent").then(|| cf.continent()).flatten(),
+            latitude: has("latitude")
+                .then_some(coordinates)
+                .flatten()
+                .map(|(latitude, _)| latitude),
+            longitude: has("longitude")
+                .then_some(coordinates)
+                .flatten()
+                .map(|(_, longitude)| longitude),
+            postal_code: has("postal_code").then(|| cf.postal_code()).flatten(),
+            metro_code: has("metro_code").then(|| cf.metro_code()).flatten(),
+            region: has("region").then(|| cf.region()).flatten(),
+            region_code: has("region_code").then(|| cf.region_code()).flatten(),
+            // `worker::Cf::timezone` panics for unknown timezones
+            timezone: has("timezone")
+                .then(|| property(request, &["timezone"])?.as_string())
+                .flatten()
+                .and_then(non_empty),
+            client_tcp_rtt: has("client_tcp_rtt")
+                .then(|| number(request, &["clientTcpRtt"]))
+                .flatten(),
+            bot_score: has("bot_score")
+                .then(|| number(request, &["botManagement", "score"]))
+                .flatten(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    #[test]
+    fn test_fields() {
+        let all = Fields::default();
+        assert!(FIELDS.iter().all(|field| all.includes(field)));
+
+        let allowed = Fields::default().with_allow("colo, country").unwrap();
+        assert!(allowed.includes("colo"));
+        assert!(!allowed.includes("postal_code"));
+
+        let denied = Fields::default()
+            .with_deny("postal_code, latitude, longitude")
+            .unwrap();
+        assert!(denied.includes("colo"));
+        assert!(!denied.includes("latitude"));
+
+        let both = allowed.with_deny("country").unwrap();
+        assert!(both.includes("colo"));
+        assert!(!both.includes("country"));
+
+        let none = Fields::default().with_allow("").unwrap();
+        assert!(!none.includes("colo"));
+
+        assert!(Fields::default().with_allow("colo, lol").is_err());
+        assert!(Fields::default().with_deny("lol").is_err());
+    }
+
+    #[test]
+    fn test_parse_request_priority() {
+        assert_eq!(
+            RequestPriority::parse("weight=192;exclusive=0;group=3;group-weight=127"),
+            Some(RequestPriority {
+                weight: 192,
+                exclusive: false,
+                group: 3,
+                group_weight: 127,
+            })
+        );
+        assert_eq!(
+            RequestPriority::parse("weight=256;exclusive=1;urgency=3;incremental"),
+            Some(RequestPriority {
+                weight: 256,
+                exclusive: true,
+                group: 0,
+                group_weight: 0,
+            })
+        );
+        assert_eq!(RequestPriority::parse(""), None);
+        assert_eq!(RequestPriority::parse("urgency=3;incremental"), None);
+        assert_eq!(RequestPriority::parse("weight=high"), None);
+    }
+
+    #[test]
+    fn test_omit_absent() {
+        let cloudflare = Cloudflare {
+            colo: Some("FRA".to_string()),
+            asn: Some(3320),
+            client_tcp_rtt: Some(23),
+            ..Cloudflare::default()
+        };
+        assert_eq!(
+            serde_json::to_value(cloudflare).unwrap(),
+            json!({ "colo": "FRA", "asn": 3320, "client_tcp_rtt": 23 })
+        );
+    }
+
+    #[test]
+    fn test_all_fields_selectable() {
+        let cloudflare = Cloudflare {
+            colo: Some(String::new()),
+            asn: Some(0),
+            country: Some(String::new()),
+            http_protocol: Some(String::new()),
+            request_priority: Some(RequestPriority {
+                weight: 0,
+                exclusive: false,
+                group: 0,
+                group_weight: 0,
+            }),
+            tls_version: Some(String::new()),
+            tls_cipher: Some(String::new()),
+            tls_client_auth: None,
+            city: Some(String::new()),
+            continent: Some(String::new()),
+            latitude: Some(0.0),
+            longitude: Some(0.0),
+            postal_code: Some(String::new()),
+            metro_code: Some(String::new()),
+            region: Some(String::new()),
+            region_code: Some(String::new()),
+            timezone: Some(String::new()),
+            client_tcp_rtt: Some(0),
+            bot_score: Some(0),
+        };
+        let value = serde_json::to_value(cloudflare).unwrap();
+        let mut names: Vec<&str> = value
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        names.push("tls_client_auth");
+        names.sort_unstable();
+        let mut fields = FIELDS.to_vec();
+        fields.sort_unstable();
+        assert_eq!(names, fields);
+    }
+}
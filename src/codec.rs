@@ -0,0 +1,685 @@
+//! Encoding of upstream URLs into forwarding URLs and back
+//!
+//! The feed rewriter and the forwarding route have to agree on how the
+//! original mp3 URL is stored inside a forwarding URL. Both sides use this
+//! module, so `decode(&encode(forward_url, &url), prefix) == url` holds for
+//! every upstream URL.
+//!
+//! There are three formats:
+//!
+//! * `ref`: the full upstream URL is stored in the `ref` query parameter.
+//!   `https://example.com/podcast1.mp3?foo=bar&baz=1` becomes
+//!   `https://forwarder.dev/r/podcast1.mp3?ref=https%3A%2F%2Fexample.com%2Fpodcast1.mp3%3Ffoo%3Dbar%26baz%3D1`
+//! * `token`: the forwarding URL already contains the upstream path, so only
+//!   the rest of the upstream URL (scheme, host, query and fragment) is stored
+//!   as a versioned base64url token in the `fwd` query parameter. The token is
+//!   deflated if that makes it shorter and sealed with a key derived from the
+//!   `TOKEN_SECRET` secret, so it can neither be read nor forged. The upstream
+//!   path is authenticated as well, so tokens can't be moved to other paths.
+//!   `https://example.com/podcast1.mp3` becomes
+//!   `https://forwarder.dev/r/podcast1.mp3?fwd=Acl3u-UFkQPzN35bsuSL…`
+//! * `path`: scheme, host and path of the upstream URL are embedded into the
+//!   path, so forwarding still works for clients which strip query strings.
+//!   `https://example.com/podcast1.mp3?foo=bar` becomes
+//!   `https://forwarder.dev/r/https/example.com/podcast1.mp3?foo=bar`
+//!
+//! All formats are always accepted when decoding, so switching the format
+//! doesn't break forwarding URLs in feeds which were fetched before.
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::str::FromStr;
+use url::Position;
+use urlencoding::decode as percent_decode;
+use worker::{Error, Result, Url};
+
+/// Name of the query parameter holding the upstream URL
+pub const REF_PARAM: &str = "ref";
+
+/// Name of the query parameter holding the upstream token
+///
+/// Unlike `t`, it doesn't clash with parameters of the feed request, which
+/// are kept in forwarding URLs.
+pub const TOKEN_PARAM: &str = "fwd";
+
+/// Current version of the token format. It is stored as first byte of every
+/// token, so the format can be changed without breaking older tokens.
+const TOKEN_VERSION: u8 = 1;
+
+/// Flag for uncompressed token payloads
+const RAW: u8 = 0;
+
+/// Flag for deflated token payloads
+const DEFLATED: u8 = 1;
+
+/// Length of the nonce stored in every token
+const NONCE_LEN: usize = 12;
+
+/// Upper bound for inflated token payloads, so forged tokens can't make us
+/// allocate arbitrary amounts of memory
+const MAX_PAYLOAD: usize = 8 * 1024;
+
+/// Key for sealing and opening tokens
+///
+/// It is derived from the `TOKEN_SECRET` secret. Changing the secret breaks
+/// all tokens in feeds which were fetched before.
+#[derive(Clone)]
+pub struct TokenKey {
+    /// Key of the cipher
+    cipher: [u8; 32],
+    /// Key for deriving nonces from the payload
+    nonce: [u8; 32],
+}
+
+impl TokenKey {
+    /// Derive a token key from the given secret
+    #[must_use]
+    pub fn new(secret: &str) -> Self {
+        Self {
+            cipher: hmac(secret.as_bytes(), &[b"cipher"]),
+            nonce: hmac(secret.as_bytes(), &[b"nonce"]),
+        }
+    }
+
+    /// Seal the payload, authenticating the upstream path as well
+    ///
+    /// The nonce is derived from path and payload, so the same upstream URL
+    /// always results in the same token and rewritten feeds stay stable.
+    fn seal(&self, path: &str, payload: &[u8]) -> Vec<u8> {
+        let nonce = hmac(&self.nonce, &[path.as_bytes(), &[0], payload]);
+        let nonce = Nonce::from_slice(&nonce[..NONCE_LEN]);
+        let ciphertext = ChaCha20Poly1305::new(&self.cipher.into())
+            .encrypt(
+                nonce,
+                Payload {
+                    msg: payload,
+                    aad: path.as_bytes(),
+                },
+            )
+            // Encryption only fails for payloads larger than 256 GiB
+            .unwrap_or_default();
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        sealed
+    }
+
+    /// Open a sealed payload, failing if it was tampered with or moved to a
+    /// different path
+    fn open(&self, path: &str, sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        ChaCha20Poly1305::new(&self.cipher.into())
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: path.as_bytes(),
+                },
+            )
+            .ok()
+    }
+}
+
+/// HMAC-SHA256 of the concatenated parts
+fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    // HMAC accepts keys of any length
+    let Ok(mut mac) = <Hmac<Sha256> as Mac>::new_from_slice(key) else {
+        unreachable!()
+    };
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// Format used for storing upstream URLs in forwarding URLs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    /// Full upstream URL in the `ref` parameter
+    #[default]
+    Ref,
+    /// Sealed token in the `fwd` parameter
+    Token,
+    /// Upstream URL embedded in the path
+    Path,
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ref" => Ok(Self::Ref),
+            "token" => Ok(Self::Token),
+            "path" => Ok(Self::Path),
+            _ => Err(Error::RustError(format!("Unknown forward URL mode: {s}"))),
+        }
+    }
+}
+
+/// HTML-escape a forwarding URL, so it can be embedded into the feed as is
+fn escape(forward_url: &Url) -> String {
+    // Escape `&` character as HTML entities to make feed readable in browser
+    // See https://stackoverflow.com/a/17918240/270334
+    // See https://docs.rs/html-escape/latest/html_escape/
+    html_escape::encode_text(forward_url.as_str()).to_string()
+}
+
+/// Attach `original` as `ref` parameter to the given forwarding URL
+///
+/// The result is HTML-escaped, so it can be embedded into the feed as is.
+#[must_use]
+pub fn encode(mut forward_url: Url, original: &Url) -> String {
+    forward_url
+        .query_pairs_mut()
+        .append_pair(REF_PARAM, original.as_str());
+    escape(&forward_url)
+}
+
+/// Attach `original` as sealed token to the given forwarding URL
+///
+/// The path of `original` is not part of the token. It gets restored from the
+/// path of the forwarding URL, so that has to end with the upstream path.
+/// The result is HTML-escaped, so it can be embedded into the feed as is.
+#[must_use]
+pub fn encode_token(mut forward_url: Url, original: &Url, key: &TokenKey) -> String {
+    let mut rest = original.clone();
+    rest.set_path("");
+    let rest = rest.as_str().as_bytes();
+    let deflated = miniz_oxide::deflate::compress_to_vec(rest, 10);
+    let mut payload = Vec::with_capacity(rest.len() + 1);
+    if deflated.len() < rest.len() {
+        payload.push(DEFLATED);
+        payload.extend(deflated);
+    } else {
+        payload.push(RAW);
+        payload.extend_from_slice(rest);
+    }
+    let mut token = vec![TOKEN_VERSION];
+    token.extend(key.seal(original.path(), &payload));
+    let token = base64::encode_config(token, base64::URL_SAFE_NO_PAD);
+    forward_url
+        .query_pairs_mut()
+        .append_pair(TOKEN_PARAM, &token);
+    escape(&forward_url)
+}
+
+/// Embed `original` into the path of the given forwarding URL
+///
+/// The forwarding path becomes `{prefix}/{scheme}/{host}{path}` and the query
+/// of `original` is passed through as is. Upstream URLs with a `ref` or `fwd`
+/// parameter in their query are ambiguous in this format.
+/// The result is HTML-escaped, so it can be embedded into the feed as is.
+#[must_use]
+pub fn encode_path(mut forward_url: Url, original: &Url, prefix: Option<&str>) -> String {
+    forward_url.set_path(&format!(
+        "{}/{}/{}{}",
+        prefix.unwrap_or_default(),
+        original.scheme(),
+        &original[Position::BeforeHost..Position::AfterPort],
+        original.path()
+    ));
+    forward_url.set_query(original.query());
+    forward_url.set_fragment(original.fragment());
+    escape(&forward_url)
+}
+
+/// Strip the route prefix from the path of a forwarding URL
+fn strip_prefix<'a>(path: &'a str, prefix: Option<&str>) -> Result<&'a str> {
+    prefix.map_or(Ok(path), |prefix| {
+        path.strip_prefix(prefix).ok_or_else(|| {
+            Error::RustError(format!("Forward URL does not start with `{prefix}` prefix"))
+        })
+    })
+}
+
+/// Restore the upstream URL from a forwarding URL with an embedded path
+fn decode_path(url: &Url, prefix: Option<&str>) -> Result<Url> {
+    let path = strip_prefix(url.path(), prefix)?;
+    let mut parts = path.trim_start_matches('/').splitn(3, '/');
+    let (Some(scheme @ ("http" | "https")), Some(host)) = (parts.next(), parts.next()) else {
+        return Err(Error::RustError(
+            "Could not find ref parameter or upstream URL in path".to_string(),
+        ));
+    };
+    let mut upstream = Url::parse(&format!(
+        "{scheme}://{host}/{}",
+        parts.next().unwrap_or_default()
+    ))?;
+    upstream.set_query(url.query());
+    upstream.set_fragment(url.fragment());
+    Ok(upstream)
+}
+
+/// Check if `url` is a forwarding URL of the forwarder at `forwarder`, i.e.
+/// it has the same origin and starts with the route prefix
+#[must_use]
+pub fn is_forwarding_url(url: &Url, forwarder: &Url, prefix: Option<&str>) -> bool {
+    url.origin() == forwarder.origin()
+        && prefix.is_none_or(|prefix| url.path().starts_with(&format!("{prefix}/")))
+}
+
+/// Resolve nested forwarding URLs of the forwarder at `forwarder`
+///
+/// Feeds which went through the forwarder more than once can contain
+/// forwarding URLs pointing at forwarding URLs. Redirecting to these would
+/// just send the client back to us, so they get unwrapped.
+///
+/// # Errors
+///
+/// * a nested forwarding URL cannot be decoded
+/// * there are more than `max_hops` levels of nesting
+pub fn unwrap_nested(
+    mut url: Url,
+    forwarder: &Url,
+    prefix: Option<&str>,
+    key: Option<&TokenKey>,
+    max_hops: u32,
+) -> Result<Url> {
+    let mut hops = 0;
+    while is_forwarding_url(&url, forwarder, prefix) {
+        hops += 1;
+        if hops > max_hops {
+            return Err(Error::RustError(format!(
+                "Forwarding loop detected after {max_hops} hops: {url}"
+            )));
+        }
+        url = decode(url.as_str(), prefix, key)?;
+    }
+    Ok(url)
+}
+
+/// Restore the upstream URL from a token and the forwarding URL path
+fn decode_token(
+    token: &str,
+    path: &str,
+    prefix: Option<&str>,
+    key: Option<&TokenKey>,
+) -> Result<Url> {
+    let key = key.ok_or_else(|| {
+        Error::RustError("Cannot decode token without `TOKEN_SECRET`".to_string())
+    })?;
+    let invalid = || Error::RustError(format!("Invalid token {token}"));
+    let decoded = base64::decode_config(token, base64::URL_SAFE_NO_PAD)
+        .map_err(|e| Error::RustError(format!("Cannot decode token {token}: {e}")))?;
+    let sealed = match decoded.split_first() {
+        Some((&TOKEN_VERSION, sealed)) => sealed,
+        Some((version, _)) => {
+            return Err(Error::RustError(format!(
+                "Unsupported token version {version}"
+            )))
+        }
+        None => return Err(Error::RustError("Empty token".to_string())),
+    };
+    let path = strip_prefix(path, prefix)?;
+    let payload = key.open(path, sealed).ok_or_else(invalid)?;
+    let rest = match payload.split_first() {
+        Some((&RAW, rest)) => rest.to_vec(),
+        Some((&DEFLATED, deflated)) => {
+            miniz_oxide::inflate::decompress_to_vec_with_limit(deflated, MAX_PAYLOAD)
+                .map_err(|_| invalid())?
+        }
+        _ => return Err(invalid()),
+    };
+    let rest = String::from_utf8(rest).map_err(|_| invalid())?;
+    let mut upstream = Url::parse(&rest)?;
+    upstream.set_path(path);
+    Ok(upstream)
+}
+
+/// Extract the upstream URL from a (possibly HTML-escaped) forwarding URL
+///
+/// All formats are supported. For tokens and embedded paths, `prefix` gets
+/// stripped from the path of the forwarding URL to get the upstream path.
+/// Tokens can only be opened with the `key` they were sealed with.
+/// If neither a valid `fwd` nor `ref` parameter is present, the upstream URL
+/// is taken from the path.
+/// Older feeds contain doubly percent-encoded `ref` parameters
+/// (e.g. `ref=https%253A%252F%252Fexample.com`). These are still supported.
+///
+/// # Errors
+///
+/// * the forwarding URL is not a valid URL
+/// * neither a `ref` nor a `fwd` parameter nor an upstream URL in the path
+///   is present
+/// * a token cannot be opened with `key`
+pub fn decode(forward_url: &str, prefix: Option<&str>, key: Option<&TokenKey>) -> Result<Url> {
+    let html_decoded = html_escape::decode_html_entities(forward_url);
+    let url = Url::parse(&html_decoded)?;
+    decode_query(&url, prefix, key).or_else(|e| decode_path(&url, prefix).map_err(|_| e))
+}
+
+/// Extract the upstream URL from the `fwd` or `ref` parameter
+fn decode_query(url: &Url, prefix: Option<&str>, key: Option<&TokenKey>) -> Result<Url> {
+    if let Some((_, token)) = url.query_pairs().find(|(k, _)| k == TOKEN_PARAM) {
+        return decode_token(&token, url.path(), prefix, key);
+    }
+    let Some((_, reference)) = url.query_pairs().find(|(k, _)| k == REF_PARAM) else {
+        return Err(Error::RustError(
+            "Could not find ref parameter or upstream URL in path".to_string(),
+        ));
+    };
+    if let Ok(upstream) = Url::parse(&reference) {
+        return Ok(upstream);
+    }
+    let decoded = percent_decode(&reference)
+        .map_err(|e| Error::RustError(format!("Cannot decode ref {reference}: {e}")))?;
+    Ok(Url::parse(&decoded)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+    use proptest::prelude::*;
+
+    fn forward_url() -> Url {
+        Url::parse("https://example.org/r/podcast.mp3").unwrap()
+    }
+
+    #[test]
+    fn test_encode() {
+        let original = Url::parse("https://example.com/some/podcast3.mp3?bla=blub123").unwrap();
+        assert_eq!(
+            encode(forward_url(), &original),
+            "https://example.org/r/podcast.mp3?ref=https%3A%2F%2Fexample.com%2Fsome%2Fpodcast3.mp3%3Fbla%3Dblub123"
+        );
+    }
+
+    #[test]
+    fn test_decode_legacy() {
+        let forward_url =
+            "https://example.org/r/podcast1.mp3?ref=https%253A%252F%252Fexample.com%252Fpodcast1.mp3";
+        assert_eq!(
+            decode(forward_url, None, None).unwrap(),
+            Url::parse("https://example.com/podcast1.mp3").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_decode_html_escaped() {
+        let forward_url = "https://example.org/r/podcast1.mp3?foo=1&amp;ref=https%3A%2F%2Fexample.com%2Fpodcast1.mp3";
+        assert_eq!(
+            decode(forward_url, None, None).unwrap(),
+            Url::parse("https://example.com/podcast1.mp3").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_decode_missing_ref() {
+        assert!(decode("https://example.org/r/podcast1.mp3", None, None).is_err());
+        assert!(decode("https://example.org/r/podcast1.mp3?ref=lol", None, None).is_err());
+    }
+
+    fn key() -> TokenKey {
+        TokenKey::new("secret")
+    }
+
+    #[test]
+    fn test_encode_token() {
+        let original = Url::parse("https://example.com/podcast1.mp3").unwrap();
+        let forward_url = Url::parse("https://forwarder.dev/r/podcast1.mp3?t=10").unwrap();
+        let encoded = encode_token(forward_url, &original, &key());
+        assert!(encoded.starts_with("https://forwarder.dev/r/podcast1.mp3?t=10&amp;fwd=A"));
+        assert!(!encoded.contains("example.com"));
+        assert_eq!(
+            decode(&encoded, Some("/r"), Some(&key())).unwrap(),
+            original
+        );
+        // Tokens are deterministic, so rewritten feeds don't change
+        assert_eq!(
+            encode_token(
+                Url::parse("https://forwarder.dev/r/podcast1.mp3?t=10").unwrap(),
+                &original,
+                &key()
+            ),
+            encoded
+        );
+    }
+
+    #[test]
+    fn test_encode_token_compressed() {
+        let original = Url::parse(&format!(
+            "https://example.com/podcast1.mp3?{}",
+            "awCollectionId=123&awEpisodeId=456&".repeat(8)
+        ))
+        .unwrap();
+        let forward_url = Url::parse("https://forwarder.dev/r/podcast1.mp3").unwrap();
+        let encoded = encode_token(forward_url, &original, &key());
+        assert!(encoded.len() < original.as_str().len());
+        assert_eq!(
+            decode(&encoded, Some("/r"), Some(&key())).unwrap(),
+            original
+        );
+    }
+
+    #[test]
+    fn test_decode_token_errors() {
+        let original = Url::parse("https://example.com/podcast1.mp3").unwrap();
+        let forward_url = Url::parse("https://forwarder.dev/r/podcast1.mp3").unwrap();
+        let encoded = encode_token(forward_url, &original, &key());
+        let token = encoded.split("fwd=").nth(1).unwrap();
+        // missing key
+        assert!(decode(&encoded, Some("/r"), None).is_err());
+        // wrong key
+        assert!(decode(&encoded, Some("/r"), Some(&TokenKey::new("other"))).is_err());
+        // wrong prefix
+        assert!(decode(
+            &format!("https://forwarder.dev/x/podcast1.mp3?fwd={token}"),
+            Some("/r"),
+            Some(&key())
+        )
+        .is_err());
+        // moved to a different path
+        assert!(decode(
+            &format!("https://forwarder.dev/r/podcast2.mp3?fwd={token}"),
+            Some("/r"),
+            Some(&key())
+        )
+        .is_err());
+        // tampered
+        let mut tampered = base64::decode_config(token, base64::URL_SAFE_NO_PAD).unwrap();
+        *tampered.last_mut().unwrap() ^= 1;
+        let tampered = base64::encode_config(tampered, base64::URL_SAFE_NO_PAD);
+        assert!(decode(
+            &format!("https://forwarder.dev/r/podcast1.mp3?fwd={tampered}"),
+            Some("/r"),
+            Some(&key())
+        )
+        .is_err());
+        // unknown version
+        assert!(decode(
+            "https://forwarder.dev/r/podcast1.mp3?fwd=Amh0dHBzOi8vZXhhbXBsZS5jb20v",
+            Some("/r"),
+            Some(&key())
+        )
+        .is_err());
+        // not base64
+        assert!(decode(
+            "https://forwarder.dev/r/podcast1.mp3?fwd=!!!",
+            Some("/r"),
+            Some(&key())
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_encode_path() {
+        let original = Url::parse("https://example.com:8080/some/podcast1.mp3?foo=bar").unwrap();
+        let encoded = encode_path(
+            Url::parse("https://forwarder.dev/").unwrap(),
+            &original,
+            Some("/r"),
+        );
+        assert_eq!(
+            encoded,
+            "https://forwarder.dev/r/https/example.com:8080/some/podcast1.mp3?foo=bar"
+        );
+        assert_eq!(decode(&encoded, Some("/r"), None).unwrap(), original);
+    }
+
+    #[test]
+    fn test_decode_path() {
+        // Query string stripped by the client
+        assert_eq!(
+            decode(
+                "https://forwarder.dev/r/https/example.com/episodes/1/stream.mp3",
+                Some("/r"),
+                None
+            )
+            .unwrap(),
+            Url::parse("https://example.com/episodes/1/stream.mp3").unwrap()
+        );
+        assert_eq!(
+            decode(
+                "https://forwarder.dev/media/http/example.com/a.mp3?t=10",
+                Some("/media"),
+                None
+            )
+            .unwrap(),
+            Url::parse("http://example.com/a.mp3?t=10").unwrap()
+        );
+        assert!(decode(
+            "https://forwarder.dev/r/episodes/1/stream.mp3",
+            Some("/r"),
+            None
+        )
+        .is_err());
+        assert!(decode(
+            "https://forwarder.dev/r/ftp/example.com/a.mp3",
+            Some("/r"),
+            None
+        )
+        .is_err());
+        assert!(decode(
+            "https://forwarder.dev/x/https/example.com/a.mp3",
+            Some("/r"),
+            None
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_is_forwarding_url() {
+        let forwarder = Url::parse("https://forwarder.dev/").unwrap();
+        let is_forwarding =
+            |url: &str| is_forwarding_url(&Url::parse(url).unwrap(), &forwarder, Some("/r"));
+        assert!(is_forwarding("https://forwarder.dev/r/podcast1.mp3?ref=x"));
+        assert!(!is_forwarding("https://forwarder.dev/podcast1.mp3"));
+        assert!(!is_forwarding("https://forwarder.dev/rss/podcast1.mp3"));
+        assert!(!is_forwarding("http://forwarder.dev/r/podcast1.mp3"));
+        assert!(!is_forwarding("https://example.com/r/podcast1.mp3"));
+    }
+
+    #[test]
+    fn test_unwrap_nested() {
+        let forwarder = Url::parse("https://forwarder.dev/").unwrap();
+        let original = Url::parse("https://example.com/podcast1.mp3").unwrap();
+        let mut url = original.clone();
+        for _ in 0..3 {
+            let mut forward_url = forwarder.clone();
+            forward_url.set_path("/r/podcast1.mp3");
+            url = Url::parse(&encode(forward_url, &url)).unwrap();
+        }
+        assert_eq!(
+            unwrap_nested(url.clone(), &forwarder, Some("/r"), None, 3).unwrap(),
+            original
+        );
+        assert!(unwrap_nested(url, &forwarder, Some("/r"), None, 2).is_err());
+        assert_eq!(
+            unwrap_nested(original.clone(), &forwarder, Some("/r"), None, 0).unwrap(),
+            original
+        );
+    }
+
+    #[test]
+    fn test_mode_from_str() {
+        assert_eq!("ref".parse::<Mode>().unwrap(), Mode::Ref);
+        assert_eq!("token".parse::<Mode>().unwrap(), Mode::Token);
+        assert_eq!("path".parse::<Mode>().unwrap(), Mode::Path);
+        assert!("lol".parse::<Mode>().is_err());
+    }
+
+    #[test]
+    fn test_round_trip_examples() {
+        for url in [
+            "https://example.com/podcast.mp3?awCollectionId=omr_abd3eb&amp;awEpisodeId=585475",
+            "https://example.com/a+b/c%2Fd.mp3?q=1+2&r=%2F",
+            "https://example.com/episode.mp3?next=https%3A%2F%2Fexample.org%2F%3Fa%3D1%26b%3D2",
+            "https://example.com/über/folge.mp3#t=10",
+            "http://dts.podtrac.com/redirect.mp3/example.com/podcast.mp3",
+        ] {
+            let original = Url::parse(url).unwrap();
+            assert_eq!(
+                decode(&encode(forward_url(), &original), None, None).unwrap(),
+                original
+            );
+        }
+    }
+
+    /// Arbitrary upstream URLs including unicode paths, HTML entities,
+    /// reserved characters, nested query strings and fragments
+    fn upstream_url() -> impl Strategy<Value = Url> {
+        let segment = prop_oneof![
+            "[a-zA-Z0-9._~-]{1,12}",
+            "\\PC{1,8}",
+            Just("%2F".to_string()),
+            Just("a+b".to_string()),
+            Just("&amp;".to_string()),
+        ];
+        let query = prop_oneof![
+            "[a-z]{1,5}=[a-zA-Z0-9+ /%&;=?]{0,16}",
+            Just("a=1&amp;b=2".to_string()),
+            Just("next=https%3A%2F%2Fexample.org%2F%3Fa%3D1%26b%3D2".to_string()),
+            "\\PC{0,8}",
+        ];
+        (
+            prop_oneof![Just("http"), Just("https")],
+            "[a-z0-9-]{1,10}\\.(com|org|dev)",
+            prop::collection::vec(segment, 0..5),
+            prop::option::of(query),
+            prop::option::of("\\PC{0,8}"),
+        )
+            .prop_filter_map(
+                "invalid URL",
+                |(scheme, host, segments, query, fragment)| {
+                    let mut url = format!("{scheme}://{host}/{}", segments.join("/"));
+                    if let Some(query) = query {
+                        url = format!("{url}?{query}");
+                    }
+                    if let Some(fragment) = fragment {
+                        url = format!("{url}#{fragment}");
+                    }
+                    Url::parse(&url).ok()
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn test_round_trip(original in upstream_url()) {
+            prop_assert_eq!(decode(&encode(forward_url(), &original), None, None).unwrap(), original);
+        }
+
+        #[test]
+        fn test_round_trip_path(original in upstream_url()) {
+            prop_assume!(!original.query_pairs().any(|(k, _)| k == REF_PARAM || k == TOKEN_PARAM));
+            let encoded = encode_path(forward_url(), &original, Some("/r"));
+            prop_assert_eq!(decode(&encoded, Some("/r"), None).unwrap(), original);
+        }
+
+        #[test]
+        fn test_round_trip_token(original in upstream_url(), secret in "\\PC{1,16}") {
+            let key = TokenKey::new(&secret);
+            let mut forward_url = forward_url();
+            forward_url.set_path(&format!("/r{}", original.path()));
+            let encoded = encode_token(forward_url, &original, &key);
+            prop_assert_eq!(decode(&encoded, Some("/r"), Some(&key)).unwrap(), original);
+        }
+    }
+}
@@ -0,0 +1,200 @@
+//! Cross-origin resource sharing for browser-based podcast players
+//!
+//! Web players fetch the feed and the media files from another origin, so
+//! our responses need CORS headers and preflight requests (`OPTIONS`) need
+//! an answer.
+
+/// Methods allowed for cross-origin requests if not configured
+pub const DEFAULT_METHODS: &str = "GET, HEAD, OPTIONS";
+
+/// Response headers which browsers may read if not configured.
+/// Web players need `Content-Range` and `Content-Length` for seeking.
+pub const DEFAULT_EXPOSE_HEADERS: &str = "Content-Length, Content-Range, Content-Type, ETag";
+
+/// How long browsers may cache preflight responses in seconds
+const MAX_AGE: u32 = 86400;
+
+/// CORS configuration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cors {
+    /// Allowed origins, `*` allows all origins
+    origins: Vec<String>,
+    /// Allowed methods, e.g. `GET, HEAD`
+    methods: String,
+    /// Response headers exposed to the browser
+    expose_headers: String,
+}
+
+impl Cors {
+    /// Create a CORS configuration from a comma-separated list of allowed
+    /// origins, e.g. `https://openpodcast.dev, https://player.example.com`.
+    /// CORS is disabled if the list is empty.
+    #[must_use]
+    pub fn new(origins: &str) -> Self {
+        Self {
+            origins: origins
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                .map(|origin| origin.trim_end_matches('/').to_string())
+                .collect(),
+            methods: DEFAULT_METHODS.to_string(),
+            expose_headers: DEFAULT_EXPOSE_HEADERS.to_string(),
+        }
+    }
+
+    /// Override the allowed methods
+    #[must_use]
+    pub fn with_methods(mut self, methods: &str) -> Self {
+        self.methods = methods.to_string();
+        self
+    }
+
+    /// Override the response headers exposed to the browser
+    #[must_use]
+    pub fn with_expose_headers(mut self, expose_headers: &str) -> Self {
+        self.expose_headers = expose_headers.to_string();
+        self
+    }
+
+    /// Value of `Access-Control-Allow-Origin` for a request from `origin`,
+    /// or `None` if the origin is not allowed
+    fn allow_origin(&self, origin: Option<&str>) -> Option<String> {
+        if self.origins.iter().any(|allowed| allowed == "*") {
+            return Some("*".to_string());
+        }
+        let origin = origin?;
+        self.origins
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(origin))
+            .then(|| origin.to_string())
+    }
+
+    /// CORS headers for a response to a request from `origin`
+    #[must_use]
+    pub fn headers(&self, origin: Option<&str>) -> Vec<(String, String)> {
+        let Some(allow_origin) = self.allow_origin(origin) else {
+            return vec![];
+        };
+        let mut headers = vec![(
+            "Access-Control-Expose-Headers".to_string(),
+            self.expose_headers.clone(),
+        )];
+        // The response depends on the origin unless all origins are allowed
+        if allow_origin != "*" {
+            headers.push(("Vary".to_string(), "Origin".to_string()));
+        }
+        headers.push(("Access-Control-Allow-Origin".to_string(), allow_origin));
+        headers
+    }
+
+    /// CORS headers for the response to a preflight request from `origin`.
+    /// The requested headers get allowed as is.
+    #[must_use]
+    pub fn preflight_headers(
+        &self,
+        origin: Option<&str>,
+        request_headers: Option<&str>,
+    ) -> Vec<(String, String)> {
+        let mut headers = self.headers(origin);
+        if headers.is_empty() {
+            return headers;
+        }
+        headers.push((
+            "Access-Control-Allow-Methods".to_string(),
+            self.methods.clone(),
+        ));
+        if let Some(request_headers) = request_headers {
+            headers.push((
+                "Access-Control-Allow-Headers".to_string(),
+                request_headers.to_string(),
+            ));
+        }
+        headers.push(("Access-Control-Max-Age".to_string(), MAX_AGE.to_string()));
+        headers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    #[test]
+    fn test_disabled() {
+        let cors = Cors::new("");
+        assert_eq!(cors.headers(Some("https://example.com")), vec![]);
+        assert_eq!(
+            cors.preflight_headers(Some("https://example.com"), Some("range")),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn test_any_origin() {
+        let cors = Cors::new("*");
+        let headers = cors.headers(None);
+        assert_eq!(header(&headers, "Access-Control-Allow-Origin"), Some("*"));
+        assert_eq!(header(&headers, "Vary"), None);
+        assert_eq!(
+            header(&headers, "Access-Control-Expose-Headers"),
+            Some(DEFAULT_EXPOSE_HEADERS)
+        );
+    }
+
+    #[test]
+    fn test_allowed_origins() {
+        let cors = Cors::new("https://openpodcast.dev/, https://player.example.com");
+        let headers = cors.headers(Some("https://player.example.com"));
+        assert_eq!(
+            header(&headers, "Access-Control-Allow-Origin"),
+            Some("https://player.example.com")
+        );
+        assert_eq!(header(&headers, "Vary"), Some("Origin"));
+        assert_eq!(
+            header(
+                &cors.headers(Some("https://openpodcast.dev")),
+                "Access-Control-Allow-Origin"
+            ),
+            Some("https://openpodcast.dev")
+        );
+        assert_eq!(cors.headers(Some("https://evil.example.com")), vec![]);
+        assert_eq!(cors.headers(None), vec![]);
+    }
+
+    #[test]
+    fn test_preflight() {
+        let cors = Cors::new("*")
+            .with_methods("GET")
+            .with_expose_headers("ETag");
+        let headers = cors.preflight_headers(Some("https://example.com"), Some("range"));
+        assert_eq!(
+            header(&headers, "Access-Control-Allow-Methods"),
+            Some("GET")
+        );
+        assert_eq!(
+            header(&headers, "Access-Control-Allow-Headers"),
+            Some("range")
+        );
+        assert_eq!(
+            header(&headers, "Access-Control-Expose-Headers"),
+            Some("ETag")
+        );
+        assert_eq!(header(&headers, "Access-Control-Max-Age"), Some("86400"));
+        assert_eq!(
+            header(
+                &cors.preflight_headers(None, None),
+                "Access-Control-Allow-Headers"
+            ),
+            None
+        );
+    }
+}
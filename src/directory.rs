@@ -0,0 +1,382 @@
+//! Tracking of feed fetches by podcast directories
+//!
+//! After publishing an episode, it takes a while until directories like Apple
+//! Podcasts, Spotify or Podcast Index pick it up. Feed fetches of directory
+//! crawlers get recorded together with the version of the upstream feed they
+//! received, so we can tell which directories have seen the latest episode.
+//!
+//! The latest version is the one the upstream server returned last, for any
+//! client. Crawls and the latest version are kept in the `Directories` Durable
+//! Object, so they cover all isolates and survive deploys.
+
+use crate::{durable, headers, rss};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use worker::{
+    async_trait, durable_object, js_sys, wasm_bindgen, wasm_bindgen_futures, worker_sys, Env,
+    Request, Response, Result, State,
+};
+
+/// Binding of the `Directories` Durable Object in `wrangler.toml`
+const BINDING: &str = "DIRECTORIES";
+
+/// Name of the single `Directories` Durable Object
+const NAME: &str = "directories";
+
+/// Storage key of the `Tracker`
+const TRACKER_KEY: &str = "tracker";
+
+/// Maximum number of directories which get tracked. Directories seen for the
+/// first time are ignored beyond that. This also keeps the `Tracker` below
+/// the size limit of values in Durable Object storage.
+const MAX_DIRECTORIES: usize = 100;
+
+/// Latest upstream version this isolate sent to the `Directories` Durable
+/// Object, so unchanged versions don't get sent again
+static FETCHED: LazyLock<Mutex<Option<Version>>> = LazyLock::new(Mutex::default);
+
+/// Version of the upstream feed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Version {
+    /// `ETag` of the upstream feed, computed from its body
+    pub etag: String,
+    /// Guid (or title) of the newest episode in the upstream feed
+    pub latest_episode: Option<String>,
+}
+
+impl Version {
+    /// Version of the given upstream feed
+    #[must_use]
+    pub fn of(feed: &str) -> Self {
+        Self {
+            etag: headers::etag(feed),
+            latest_episode: rss::latest_episode(feed),
+        }
+    }
+
+    /// Check if this version has the newest episode of the `latest` version.
+    /// Feeds without episodes are compared by their `ETag` instead
+    #[must_use]
+    pub fn has_latest_episode(&self, latest: &Self) -> bool {
+        latest.latest_episode.as_ref().map_or_else(
+            || self.etag == latest.etag,
+            |episode| self.latest_episode.as_ref() == Some(episode),
+        )
+    }
+}
+
+/// Last crawl of a directory
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Crawl {
+    /// Name of the directory, e.g. `Apple Podcasts - directory`
+    pub directory: String,
+    /// Time of the last crawl in milliseconds since the epoch
+    #[serde(rename = "last_crawl")]
+    pub time: u64,
+    /// Number of crawls
+    pub crawls: u64,
+    /// Version of the feed the directory received last, if known. Crawls
+    /// answered with `304 Not Modified` by the upstream server keep the
+    /// previous version.
+    pub version: Option<Version>,
+    /// Whether the directory has seen the latest episode of the feed
+    pub seen_latest_episode: bool,
+}
+
+/// Crawls of directories and the latest version of the feed
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Tracker {
+    crawls: HashMap<String, Crawl>,
+    latest: Option<Version>,
+}
+
+impl Tracker {
+    /// Remember the version of the upstream feed which was just fetched for
+    /// any client
+    pub fn fetched(&mut self, version: &Version) {
+        self.latest = Some(version.clone());
+    }
+
+    /// Record a crawl of `directory` at time `now` (in milliseconds)
+    pub fn record(&mut self, directory: &str, now: u64, version: Option<Version>) {
+        if let Some(version) = &version {
+            self.fetched(version);
+        }
+        if let Some(crawl) = self.crawls.get_mut(directory) {
+            crawl.time = now;
+            crawl.crawls += 1;
+            if version.is_some() {
+                crawl.version = version;
+            }
+        } else if self.crawls.len() < MAX_DIRECTORIES {
+            self.crawls.insert(
+                directory.to_string(),
+                Crawl {
+                    directory: directory.to_string(),
+                    time: now,
+                    crawls: 1,
+                    version,
+                    seen_latest_episode: false,
+                },
+            );
+        }
+    }
+
+    /// Crawls of all directories, most recent first
+    #[must_use]
+    pub fn crawls(&self) -> Vec<Crawl> {
+        let mut crawls: Vec<Crawl> = self
+            .crawls
+            .values()
+            .map(|crawl| Crawl {
+                seen_latest_episode: crawl
+                    .version
+                    .as_ref()
+                    .zip(self.latest.as_ref())
+                    .is_some_and(|(version, latest)| version.has_latest_episode(latest)),
+                ..crawl.clone()
+            })
+            .collect();
+        crawls.sort_by(|a, b| {
+            b.time
+                .cmp(&a.time)
+                .then_with(|| a.directory.cmp(&b.directory))
+        });
+        crawls
+    }
+
+    /// Latest version of the upstream feed
+    #[must_use]
+    pub const fn latest(&self) -> Option<&Version> {
+        self.latest.as_ref()
+    }
+}
+
+/// Requests sent to the `Directories` Durable Object
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Command {
+    /// Remember the version of the upstream feed which was just fetched
+    Fetched { version: Version },
+    /// Record a crawl of `directory` at time `now` (in milliseconds)
+    Record {
+        directory: String,
+        now: u64,
+        version: Option<Version>,
+    },
+    /// Return the crawls of all directories and the latest version
+    Crawls,
+}
+
+/// Crawls of all directories, most recent first, and the latest version of
+/// the upstream feed
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Report {
+    /// Latest version of the upstream feed, if it was fetched before
+    pub latest: Option<Version>,
+    /// Crawls of all directories
+    pub directories: Vec<Crawl>,
+}
+
+/// Durable Object holding the crawls of all directories
+#[durable_object]
+pub struct Directories {
+    state: State,
+}
+
+#[durable_object]
+impl DurableObject for Directories {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
+
+    // `&mut self` is required by `DurableObject`
+    #[allow(clippy::needless_pass_by_ref_mut)]
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let mut storage = self.state.storage();
+        let mut tracker: Tracker = durable::get(&storage, TRACKER_KEY)
+            .await?
+            .unwrap_or_default();
+        match req.json().await? {
+            Command::Fetched { version } => tracker.fetched(&version),
+            Command::Record {
+                directory,
+                now,
+                version,
+            } => tracker.record(&directory, now, version),
+            Command::Crawls => {
+                return Response::from_json(&Report {
+                    directories: tracker.crawls(),
+                    latest: tracker.latest().cloned(),
+                })
+            }
+        }
+        storage.put(TRACKER_KEY, &tracker).await?;
+        Response::from_json(&())
+    }
+}
+
+/// Remember the version of the upstream feed which was just fetched for any
+/// client. Versions this isolate sent before are skipped
+///
+/// # Errors
+///
+/// * the `Directories` Durable Object can't be reached
+pub async fn fetched(env: &Env, version: Version) -> Result<()> {
+    if FETCHED
+        .lock()
+        .is_ok_and(|fetched| fetched.as_ref() == Some(&version))
+    {
+        return Ok(());
+    }
+    let fetched = Command::Fetched {
+        version: version.clone(),
+    };
+    durable::call::<_, ()>(env, BINDING, NAME, "/fetched", &fetched).await?;
+    if let Ok(mut fetched) = FETCHED.lock() {
+        *fetched = Some(version);
+    }
+    Ok(())
+}
+
+/// Record a crawl of `directory` at time `now` (in milliseconds)
+///
+/// # Errors
+///
+/// * the `Directories` Durable Object can't be reached
+pub async fn record(env: &Env, directory: &str, now: u64, version: Option<Version>) -> Result<()> {
+    let record = Command::Record {
+        directory: directory.to_string(),
+        now,
+        version,
+    };
+    durable::call(env, BINDING, NAME, "/record", &record).await
+}
+
+/// Crawls of all directories and the latest version of the upstream feed
+///
+/// # Errors
+///
+/// * the `Directories` Durable Object can't be reached
+pub async fn crawls(env: &Env) -> Result<Report> {
+    durable::call(env, BINDING, NAME, "/crawls", &Command::Crawls).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    fn version(etag: &str, latest_episode: &str) -> Version {
+        Version {
+            etag: etag.to_string(),
+            latest_episode: Some(latest_episode.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_record() {
+        let mut tracker = Tracker::default();
+        tracker.record(
+            "Apple Podcasts - directory",
+            1000,
+            Some(version("a", "ep1")),
+        );
+        tracker.record("Spotify", 2000, Some(version("a", "ep1")));
+        // A new episode gets published and fetched for a listener
+        tracker.fetched(&version("b", "ep2"));
+        tracker.record("Spotify", 3000, Some(version("b", "ep2")));
+        // Unchanged according to the upstream server
+        tracker.record("Spotify", 4000, None);
+
+        assert_eq!(
+            tracker.crawls(),
+            vec![
+                Crawl {
+                    directory: "Spotify".to_string(),
+                    time: 4000,
+                    crawls: 3,
+                    version: Some(version("b", "ep2")),
+                    seen_latest_episode: true,
+                },
+                Crawl {
+                    directory: "Apple Podcasts - directory".to_string(),
+                    time: 1000,
+                    crawls: 1,
+                    version: Some(version("a", "ep1")),
+                    seen_latest_episode: false,
+                },
+            ]
+        );
+        assert_eq!(tracker.latest(), Some(&version("b", "ep2")));
+    }
+
+    #[test]
+    fn test_unknown_version() {
+        let mut tracker = Tracker::default();
+        tracker.record("Podcast Index", 1000, None);
+        assert!(!tracker.crawls()[0].seen_latest_episode);
+        assert_eq!(tracker.latest(), None);
+    }
+
+    #[test]
+    fn test_version_of() {
+        let feed = "<rss><channel><item><guid>ep2</guid></item>\
+                    <item><guid>ep1</guid></item></channel></rss>";
+        assert_eq!(
+            Version::of(feed),
+            Version {
+                etag: headers::etag(feed),
+                latest_episode: Some("ep2".to_string()),
+            }
+        );
+        assert_eq!(Version::of("<rss></rss>").latest_episode, None);
+    }
+
+    #[test]
+    fn test_has_latest_episode() {
+        // Other changes of the feed don't matter
+        assert!(version("a", "ep2").has_latest_episode(&version("b", "ep2")));
+        assert!(!version("a", "ep1").has_latest_episode(&version("b", "ep2")));
+        // Feeds without episodes are compared by their `ETag`
+        let empty = |etag: &str| Version {
+            etag: etag.to_string(),
+            latest_episode: None,
+        };
+        assert!(empty("a").has_latest_episode(&empty("a")));
+        assert!(!empty("a").has_latest_episode(&empty("b")));
+        assert!(!empty("a").has_latest_episode(&version("a", "ep1")));
+    }
+
+    #[test]
+    fn test_serialize() {
+        let crawl = Crawl {
+            directory: "Spotify".to_string(),
+            time: 1000,
+            crawls: 1,
+            version: Some(version("a", "ep1")),
+            seen_latest_episode: true,
+        };
+        assert_eq!(
+            serde_json::to_value(crawl).unwrap(),
+            serde_json::json!({
+                "directory": "Spotify",
+                "last_crawl": 1000,
+                "crawls": 1,
+                "version": { "etag": "a", "latest_episode": "ep1" },
+                "seen_latest_episode": true,
+            })
+        );
+    }
+
+    #[test]
+    fn test_record_bounded() {
+        let mut tracker = Tracker::default();
+        for i in 0..=MAX_DIRECTORIES {
+            tracker.record(&format!("Directory {i}"), 0, None);
+        }
+        assert_eq!(tracker.crawls.len(), MAX_DIRECTORIES);
+    }
+}
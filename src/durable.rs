@@ -0,0 +1,105 @@
+//! Calls of the Durable Objects keeping state across isolates
+//!
+//! Every Cloudflare location runs its own isolates and these come and go, so
+//! state kept in memory only ever covers a fraction of the requests. State
+//! which has to be exact lives in Durable Objects instead, which the worker
+//! talks to with JSON requests.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use worker::wasm_bindgen::{JsCast, JsValue};
+use worker::worker_sys::durable_object::ObjectState;
+use worker::{js_sys, wasm_bindgen_futures, Env, Error, Method, Request, RequestInit, Result};
+
+/// Send `body` as JSON to `path` of the Durable Object with the given name
+/// and parse its JSON response
+///
+/// # Errors
+///
+/// * the binding is missing in `wrangler.toml`
+/// * the Durable Object can't be reached or answers with an error
+pub async fn call<B, R>(env: &Env, binding: &str, name: &str, path: &str, body: &B) -> Result<R>
+where
+    B: Serialize,
+    R: DeserializeOwned,
+{
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_body(Some(JsValue::from_str(&serde_json::to_string(body)?)));
+    // Durable Objects only see the path, the host is arbitrary
+    let request = Request::new_with_init(&format!("https://durable-object{path}"), &init)?;
+    let stub = env
+        .durable_object(binding)?
+        .id_from_name(name)?
+        .get_stub()?;
+    let mut response = stub.fetch_with_request(request).await?;
+    if response.status_code() != 200 {
+        return Err(Error::RustError(format!(
+            "Durable Object {binding} answered with {}: {}",
+            response.status_code(),
+            response.text().await?
+        )));
+    }
+    response.json().await
+}
+
+/// Parse a value read from storage
+fn parse<T: DeserializeOwned>(value: &JsValue) -> Result<T> {
+    let json = js_sys::JSON::stringify(value)?
+        .as_string()
+        .ok_or_else(|| Error::RustError("Stored value is not JSON".to_string()))?;
+    Ok(serde_json::from_str(&json)?)
+}
+
+/// Read the value with the given key from storage, `None` if it is missing
+///
+/// Unlike `Storage::get`, failed reads are errors and not mistaken for
+/// missing keys.
+///
+/// # Errors
+///
+/// * the storage fails
+/// * the value doesn't parse as `T`
+pub async fn get<T: DeserializeOwned>(storage: &worker::Storage, key: &str) -> Result<Option<T>> {
+    // Only keys which exist are part of the result
+    let found = storage.get_multiple(vec![key]).await?;
+    let value = found.get(&JsValue::from_str(key));
+    if value.is_undefined() {
+        return Ok(None);
+    }
+    parse(&value).map(Some)
+}
+
+/// Keys and values of a storage listing, e.g. of `Storage::list_with_options`.
+/// Values which don't parse as `T` are skipped
+#[must_use]
+pub fn entries<T: DeserializeOwned>(listing: &js_sys::Map) -> Vec<(String, T)> {
+    let mut entries = Vec::new();
+    listing.for_each(&mut |value, key| {
+        if let (Some(key), Ok(value)) = (key.as_string(), parse(&value)) {
+            entries.push((key, value));
+        }
+    });
+    entries
+}
+
+/// Set the alarm of a Durable Object to `time` (in milliseconds since the
+/// epoch), replacing an earlier alarm
+///
+/// `worker` doesn't support alarms yet, so this calls `setAlarm` of the
+/// JavaScript storage API. The Durable Object needs an `alarm` method.
+///
+/// # Errors
+///
+/// * the alarm can't be set
+pub async fn set_alarm(state: &ObjectState, time: u64) -> Result<()> {
+    let storage = state.storage_internal();
+    let set_alarm: js_sys::Function =
+        js_sys::Reflect::get(&storage, &JsValue::from_str("setAlarm"))?.dyn_into()?;
+    // Timestamps in milliseconds are far below 2^53, so they convert exactly
+    #[allow(clippy::cast_precision_loss)]
+    let time = JsValue::from_f64(time as f64);
+    let promise: js_sys::Promise = set_alarm.call1(&storage, &time)?.dyn_into()?;
+    wasm_bindgen_futures::JsFuture::from(promise).await?;
+    Ok(())
+}
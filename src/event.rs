@@ -1,70 +1,846 @@
-use crate::client::client;
+//! Analytics events sent to the `OpenPodcast API`
+//!
+//! Every feed fetch and media request results in a typed event. The `kind`
+//! field tells the types apart. Events are versioned with `SCHEMA_VERSION` and
+//! a JSON Schema generated from the types is served at `/schema/event.json`,
+//! so the API can validate them.
+
+use crate::client::{Client, ClientCategory};
+use crate::cloudflare::Cloudflare;
 use crate::forward::extract_ref;
-use crate::helpers::upstream;
-use serde::ser::SerializeMap;
-use serde::{Serialize, Serializer};
-use serde_json::json;
-use worker::{Cf, Request, Result as WorkerResult, RouteContext};
+use crate::headers;
+use crate::helpers::{client_ip, cloudflare_fields, privacy, route_prefix, token_key, upstream};
+use crate::openpodcast;
+use crate::prefix;
+use crate::time;
+use schemars::schema::RootSchema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::str::FromStr;
+use uuid::Uuid;
+use worker::{console_log, Error, Method, Request, Result as WorkerResult, RouteContext, Url};
+
+/// Version of the event schema. It gets increased on every change of the
+/// event types.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Kind of request an event is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// Fetch of the feed (`GET /` or `HEAD /`)
+    Feed,
+    /// Request of a media file through a forwarding URL
+    Media,
+}
+
+impl Kind {
+    /// Kind of the request with the given path, `None` for routes without
+    /// events
+    fn from_path(path: &str, prefix: &str) -> Option<Self> {
+        if path == "/" {
+            Some(Self::Feed)
+        } else if path.starts_with(&format!("{prefix}/")) {
+            Some(Self::Media)
+        } else {
+            None
+        }
+    }
+}
+
+/// Fields of all events
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct Common {
+    /// Version of the event schema
+    pub schema_version: u32,
+    /// Unique ID of the event (UUID v4)
+    pub event_id: String,
+    /// Key for deduplicating deliveries of the event downstream. This is the
+    /// Cloudflare Ray ID of the request, or the event ID if there is none.
+    pub idempotency_key: String,
+    /// Time the request was received at the edge, RFC 3339 in UTC with
+    /// millisecond precision
+    pub timestamp: String,
+    /// URL of the upstream feed served by the forwarder
+    pub upstream: String,
+    /// HTTP method of the request, e.g. `GET`
+    pub method: String,
+    /// Status code of our response, e.g. `304` for a feed fetch which was
+    /// answered with `Not Modified`
+    pub status: u16,
+    /// Whether the client already has a copy and only wants it again if it
+    /// changed (`If-None-Match` or `If-Modified-Since`)
+    pub conditional: bool,
+    /// Whether the request exceeded the rate limit
+    pub abusive: bool,
+    /// Name of the podcast client, e.g. `Overcast`. Web players are named
+    /// after the browser brand.
+    pub client: String,
+    /// Category of the podcast client
+    pub category: ClientCategory,
+    /// Whether the client fetches without a listener, e.g. a crawler
+    pub is_bot: bool,
+    /// Browser brand from the client hints, e.g. `Google Chrome`
+    pub brand: Option<String>,
+    /// Operating system from the client hints, e.g. `Android`
+    pub platform: Option<String>,
+    /// Device model from the client hints, e.g. `Pixel 3`
+    pub model: Option<String>,
+    /// Whether the browser is on a mobile device, from the client hints
+    pub mobile: Option<bool>,
+    /// Request metadata provided by Cloudflare, including the location of
+    /// the client, see `CLOUDFLARE_FIELDS`
+    pub cloudflare: Cloudflare,
+    /// Path of the request
+    pub path: String,
+    /// All request headers as `name: value` pairs separated by `; `
+    pub headers: String,
+    /// `User-Agent` header of the request
+    pub user_agent: Option<String>,
+    /// `Origin` header of web players embedded into other websites
+    pub origin: Option<String>,
+    /// `Referer` header of web players embedded into other websites
+    pub referer: Option<String>,
+    /// IP of the client, resolved behind trusted proxies
+    pub ip: Option<String>,
+}
+
+/// Event for a fetch of the feed
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct FeedFetchEvent {
+    #[serde(flatten)]
+    pub common: Common,
+    /// Number of subscribers reported by feed readers in their user agent
+    pub subscribers: Option<u64>,
+}
+
+/// Event for a request of a media file
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct MediaRequestEvent {
+    #[serde(flatten)]
+    pub common: Common,
+    /// URL of the media file the request got forwarded to. For URLs wrapped
+    /// in measurement prefixes, this is the media URL at the end of the
+    /// chain.
+    pub upstream_ref: String,
+    /// Measurement prefixes the media URL was wrapped in, e.g. `podtrac`
+    pub prefixes: Vec<String>,
+    /// Whether the request only probes the media file (`HEAD` or a tiny byte
+    /// range), so it doesn't count as a play
+    pub probe: bool,
+}
+
+/// Event for a request of a listener who asked not to be tracked
+///
+/// It only counts the request. Which of the optional fields are present
+/// depends on the fields kept by `Privacy`, other fields are dropped.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct AnonymousEvent {
+    /// Version of the event schema
+    pub schema_version: u32,
+    /// Unique ID of the event (UUID v4)
+    pub event_id: String,
+    /// Key for deduplicating deliveries of the event downstream
+    pub idempotency_key: String,
+    /// Always `true`
+    pub anonymous: bool,
+    /// Number of requests, always `1`
+    pub count: u64,
+    /// Kind of request, kept with the `kind` field
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_kind: Option<Kind>,
+    /// Time the request was received at the edge
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+    /// URL of the upstream feed served by the forwarder
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream: Option<String>,
+    /// URL of the media file the request got forwarded to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream_ref: Option<String>,
+    /// Measurement prefixes the media URL was wrapped in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prefixes: Option<Vec<String>>,
+    /// HTTP method of the request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    /// Status code of our response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// Whether the request was conditional
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conditional: Option<bool>,
+    /// Whether the request only probed the media file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probe: Option<bool>,
+    /// Whether the request exceeded the rate limit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub abusive: Option<bool>,
+    /// Name of the podcast client
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    /// Category of the podcast client
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub category: Option<ClientCategory>,
+    /// Whether the client fetches without a listener
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_bot: Option<bool>,
+    /// Number of subscribers reported by feed readers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subscribers: Option<u64>,
+}
+
+/// Event sent to the `OpenPodcast API`, tagged with its `kind`
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+#[serde(tag = "kind")]
+pub enum Event {
+    /// Fetch of the feed
+    #[serde(rename = "feed")]
+    FeedFetch(FeedFetchEvent),
+    /// Request of a media file
+    #[serde(rename = "media")]
+    MediaRequest(MediaRequestEvent),
+    /// Request of a listener who asked not to be tracked
+    #[serde(rename = "anonymous")]
+    Anonymous(AnonymousEvent),
+}
+
+/// Key for deduplicating deliveries of an event. Events of the same request
+/// share the Ray ID Cloudflare assigned to the request.
+fn idempotency_key(ray: Option<&str>, event_id: &str) -> String {
+    ray.map(str::trim)
+        .filter(|ray| !ray.is_empty())
+        .unwrap_or(event_id)
+        .to_string()
+}
+
+/// JSON Schema of the events
+#[must_use]
+pub fn schema() -> RootSchema {
+    schemars::schema_for!(Event)
+}
+
+/// Headers which make a request conditional, i.e. the client already has a
+/// copy of the resource and only wants to fetch it again if it changed
+const CONDITIONAL_HEADERS: [&str; 2] = ["if-none-match", "if-modified-since"];
+
+/// Check if the request is a conditional request. `header` returns the value
+/// of the request header with the given name.
+fn is_conditional<F>(header: F) -> bool
+where
+    F: Fn(&str) -> Option<String>,
+{
+    CONDITIONAL_HEADERS
+        .iter()
+        .any(|name| header(name).is_some())
+}
+
+/// Check if the request only probes the media file instead of downloading
+/// it, i.e. it is a `HEAD` request or asks for a tiny byte range
+fn is_probe(request: &Request) -> bool {
+    request.method() == Method::Head
+        || request
+            .headers()
+            .get("range")
+            .ok()
+            .flatten()
+            .is_some_and(|range| headers::is_probe_range(&range))
+}
+
+/// Privacy signals a listener can send with a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    /// `DNT: 1` (Do Not Track)
+    Dnt,
+    /// `Sec-GPC: 1` (Global Privacy Control)
+    Gpc,
+}
+
+impl Signal {
+    /// Name of the request header carrying the signal
+    const fn header(self) -> &'static str {
+        match self {
+            Self::Dnt => "dnt",
+            Self::Gpc => "sec-gpc",
+        }
+    }
+}
+
+impl FromStr for Signal {
+    type Err = Error;
 
-fn request_kind(path: String) -> String {
-    if path == "/" {
-        "rss".to_string()
-    } else if path.starts_with("/r/") {
-        "mp3".to_string()
-    } else {
-        path
+    fn from_str(s: &str) -> WorkerResult<Self> {
+        match s {
+            "dnt" => Ok(Self::Dnt),
+            "gpc" => Ok(Self::Gpc),
+            _ => Err(Error::RustError(format!("Unknown privacy signal: {s}"))),
+        }
     }
 }
 
-struct Cloudflare<'a>(&'a Cf);
+/// Event fields which are kept in anonymous events by default. None of them
+/// identifies the listener. Only these fields can be kept, see
+/// `AnonymousEvent`.
+pub const ANONYMOUS_FIELDS: &[&str] = &[
+    "timestamp",
+    "kind",
+    "upstream",
+    "upstream_ref",
+    "prefixes",
+    "method",
+    "status",
+    "conditional",
+    "probe",
+    "abusive",
+    "client",
+    "category",
+    "is_bot",
+    "subscribers",
+];
+
+/// Event fields which are kept in all anonymous events
+const KEPT_FIELDS: &[&str] = &["schema_version", "event_id", "idempotency_key"];
+
+/// Which privacy signals are honored and what is left of the event if a
+/// listener sends one of them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Privacy {
+    /// Honored signals
+    signals: Vec<Signal>,
+    /// Fields kept in anonymous events
+    fields: Vec<String>,
+}
 
-impl<'a> Serialize for Cloudflare<'a> {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+impl Default for Privacy {
+    fn default() -> Self {
+        Self {
+            signals: vec![Signal::Dnt, Signal::Gpc],
+            fields: ANONYMOUS_FIELDS.iter().map(ToString::to_string).collect(),
+        }
+    }
+}
+
+impl Privacy {
+    /// Honor the given comma-separated signals, e.g. `dnt, gpc`.
+    /// An empty list disables the privacy mode.
+    ///
+    /// # Errors
+    ///
+    /// * the list contains an unknown signal
+    pub fn with_signals(mut self, signals: &str) -> WorkerResult<Self> {
+        self.signals = signals
+            .split(',')
+            .map(str::trim)
+            .filter(|signal| !signal.is_empty())
+            .map(str::parse)
+            .collect::<WorkerResult<_>>()?;
+        Ok(self)
+    }
+
+    /// Keep the given comma-separated fields in anonymous events. Fields
+    /// which are not in `ANONYMOUS_FIELDS` are dropped anyway
+    #[must_use]
+    pub fn with_fields(mut self, fields: &str) -> Self {
+        self.fields = fields
+            .split(',')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+            .map(ToString::to_string)
+            .collect();
+        self
+    }
+
+    /// Check if the listener asked not to be tracked. `header` returns the
+    /// value of the request header with the given name.
+    pub fn requested<F>(&self, header: F) -> bool
     where
-        S: Serializer,
+        F: Fn(&str) -> Option<String>,
     {
-        let mut map = serializer.serialize_map(Some(1))?;
-        map.serialize_entry("colo", &self.0.colo())?;
-        map.serialize_entry("asn", &self.0.asn())?;
-        map.serialize_entry("country", &self.0.country())?;
-        map.serialize_entry("http_protocol", &self.0.http_protocol())?;
-        map.serialize_entry("city", &self.0.city())?;
-        map.serialize_entry("continent", &self.0.continent())?;
-        map.serialize_entry("region", &self.0.region())?;
-        map.serialize_entry("region_code", &self.0.region_code())?;
-        map.end()
+        self.signals
+            .iter()
+            .any(|signal| header(signal.header()).is_some_and(|value| value.trim() == "1"))
+    }
+
+    /// Reduce the event to an anonymous aggregate count. The schema version
+    /// and the fields for deduplicating events are always kept.
+    ///
+    /// The `kind` of the result is `anonymous`, a kept kind of request moves
+    /// to `request_kind`.
+    ///
+    /// # Errors
+    ///
+    /// * the event lacks the fields which are always kept
+    pub fn anonymize(&self, event: Value) -> WorkerResult<Value> {
+        let Value::Object(event) = event else {
+            return Err(Error::RustError("Event is not an object".to_string()));
+        };
+        let mut anonymous: Map<String, Value> = event
+            .into_iter()
+            .filter(|(key, _)| KEPT_FIELDS.contains(&key.as_str()) || self.fields.contains(key))
+            .collect();
+        if let Some(kind) = anonymous.remove("kind") {
+            anonymous.insert("request_kind".to_string(), kind);
+        }
+        anonymous.insert("anonymous".to_string(), Value::Bool(true));
+        anonymous.insert("count".to_string(), json!(1));
+        let anonymous = serde_json::from_value(Value::Object(anonymous))?;
+        Ok(serde_json::to_value(Event::Anonymous(anonymous))?)
     }
 }
 
+/// Create the event of the given kind
+///
+/// Only media requests have an upstream URL, so `upstream_ref` is only called
+/// for these and feed fetches don't need a `ref`.
+fn typed<F>(
+    kind: Kind,
+    common: Common,
+    client: &Client,
+    upstream_ref: F,
+    probe: bool,
+) -> WorkerResult<Event>
+where
+    F: FnOnce() -> WorkerResult<Url>,
+{
+    Ok(match kind {
+        Kind::Feed => Event::FeedFetch(FeedFetchEvent {
+            common,
+            subscribers: client.subscribers(),
+        }),
+        Kind::Media => {
+            let chain = prefix::parse(&upstream_ref()?);
+            Event::MediaRequest(MediaRequestEvent {
+                common,
+                upstream_ref: chain.media.to_string(),
+                prefixes: chain.prefixes.iter().map(ToString::to_string).collect(),
+                probe,
+            })
+        }
+    })
+}
+
 /// Create `OpenPodcast API` event from Cloudflare request
-pub fn openpodcast<D>(request: &Request, ctx: &RouteContext<D>) -> WorkerResult<serde_json::Value> {
-    let (latitude, longitude) = request.cf().coordinates().unwrap_or((0.0, 0.0));
-    // concatenate headers into a single string separated by semi-colons
-    let headers = request
-        .headers()
-        .into_iter()
-        .map(|(key, value)| format!("{}: {}", key, value))
-        .collect::<Vec<String>>()
-        .join("; ");
-
-    // serialize `request.cf()` as json
-    let cloudflare = Cloudflare(request.cf());
-
-    let event = json!({
-        "kind":request_kind(request.path()),
-        "upstream": upstream(ctx)?,
-        "upstream-ref": extract_ref(request).map(|s| s.to_string())?,
-        "client": client(request).name(),
-        "is-bot": client(request).is_bot(),
-        "cloudflare": cloudflare,
-        "country": request.cf().country(),
-        "path": request.path(),
-        "latitude": latitude,
-        "longitude": longitude,
-        "headers": headers,
-        "user-agent": request.headers().get("user-agent").unwrap_or(None),
-        "ip": request.headers().get("x-real-ip").unwrap_or(None),
-    });
+///
+/// `received` is the time the request was received in milliseconds since
+/// the epoch. `status` is the status code of our response to the request.
+/// Requests exceeding the rate limit are flagged as `abusive`.
+///
+/// If the listener sent an honored privacy signal (`DNT` or `Sec-GPC`), the
+/// event only contains anonymous fields, see `Privacy`.
+///
+/// # Errors
+///
+/// * the request is neither a feed fetch nor a media request
+/// * the upstream URL of a media request can't be read
+pub fn openpodcast<D>(
+    request: &Request,
+    ctx: &RouteContext<D>,
+    client: &Client,
+    received: u64,
+    status: u16,
+    abusive: bool,
+) -> WorkerResult<Value> {
+    let prefix = route_prefix(ctx);
+    let path = request.path();
+    let kind = Kind::from_path(&path, &prefix)
+        .ok_or_else(|| Error::RustError(format!("No events for {path}")))?;
+    let cloudflare = Cloudflare::capture(request, &cloudflare_fields(ctx)?);
+    let event_id = Uuid::new_v4().to_string();
+    let common = Common {
+        schema_version: SCHEMA_VERSION,
+        idempotency_key: idempotency_key(request.headers().get("cf-ray")?.as_deref(), &event_id),
+        event_id,
+        timestamp: time::rfc3339(received),
+        upstream: upstream(ctx)?,
+        method: request.method().to_string(),
+        status,
+        conditional: is_conditional(|name| request.headers().get(name).ok().flatten()),
+        abusive,
+        client: client.name().to_string(),
+        category: client.category(),
+        is_bot: client.is_bot(),
+        brand: client.hints().brand.clone(),
+        platform: client.hints().platform.clone(),
+        model: client.hints().model.clone(),
+        mobile: client.hints().mobile,
+        cloudflare,
+        path,
+        headers: request
+            .headers()
+            .into_iter()
+            .map(|(key, value)| format!("{key}: {value}"))
+            .collect::<Vec<String>>()
+            .join("; "),
+        user_agent: request.headers().get("user-agent")?,
+        origin: request.headers().get("origin")?,
+        referer: request.headers().get("referer")?,
+        ip: client_ip(request, ctx)?.map(|ip| ip.to_string()),
+    };
+    let event = typed(
+        kind,
+        common,
+        client,
+        || extract_ref(request, Some(&prefix), token_key(ctx).as_ref()),
+        is_probe(request),
+    )?;
+    let event = serde_json::to_value(event)?;
 
+    let privacy = privacy(ctx)?;
+    if privacy.requested(|name| request.headers().get(name).ok().flatten()) {
+        return privacy.anonymize(event);
+    }
     Ok(event)
 }
+
+/// Event for a request, ready to be sent to the `OpenPodcast API`
+///
+/// It doesn't borrow the request, so it can be sent after the response went
+/// out.
+pub struct Delivery {
+    /// Client for the `OpenPodcast API`
+    client: openpodcast::Client,
+    /// Event for the request
+    event: Value,
+}
+
+impl Delivery {
+    /// Create the event for the request, see `openpodcast`
+    ///
+    /// # Errors
+    ///
+    /// * the `OpenPodcast API` is not configured
+    /// * the event can't be created
+    pub fn new<D>(
+        request: &Request,
+        ctx: &RouteContext<D>,
+        client: &Client,
+        received: u64,
+        status: u16,
+        abusive: bool,
+    ) -> WorkerResult<Self> {
+        Ok(Self {
+            client: openpodcast::Client::new(
+                ctx.var("OPENPODCAST_API_ENDPOINT")?.to_string(),
+                ctx.var("OPENPODCAST_API_KEY")?.to_string(),
+            ),
+            event: openpodcast(request, ctx, client, received, status, abusive)?,
+        })
+    }
+
+    /// Send the event to the `OpenPodcast API`
+    ///
+    /// The idempotency key of the event is sent as `Idempotency-Key` header,
+    /// so retried deliveries can be deduplicated.
+    ///
+    /// # Errors
+    ///
+    /// * the API can't be reached
+    pub async fn send(self) -> WorkerResult<()> {
+        let idempotency_key = self.event["idempotency_key"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        let response = self.client.send(self.event, &idempotency_key).await?;
+        console_log!("OpenPodcast API response: {:#?}", response);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use jsonschema::JSONSchema;
+    use pretty_assertions::assert_eq;
+
+    fn event() -> Value {
+        json!({
+            "schema_version": SCHEMA_VERSION,
+            "event_id": "67e55044-10b1-426f-9247-bb680e5fe0c8",
+            "idempotency_key": "7a0b1c2d3e4f5a6b-FRA",
+            "timestamp": "2022-10-18T09:30:05.123Z",
+            "kind": "media",
+            "upstream": "https://example.com/feed",
+            "upstream_ref": "https://example.com/podcast1.mp3",
+            "status": 302,
+            "client": "Overcast",
+            "category": "podcast-app",
+            "cloudflare": { "country": "DE", "latitude": 52.52, "longitude": 13.4 },
+            "headers": "dnt: 1",
+            "user_agent": "Overcast/3.0",
+            "ip": "192.0.2.1",
+        })
+    }
+
+    fn headers<'a>(headers: &'a [(&'a str, &'a str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        move |name| {
+            headers
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| (*value).to_string())
+        }
+    }
+
+    #[test]
+    fn test_signal_from_str() {
+        assert_eq!("dnt".parse::<Signal>().unwrap(), Signal::Dnt);
+        assert_eq!("gpc".parse::<Signal>().unwrap(), Signal::Gpc);
+        assert!("lol".parse::<Signal>().is_err());
+    }
+
+    #[test]
+    fn test_requested() {
+        let privacy = Privacy::default();
+        assert!(privacy.requested(headers(&[("dnt", "1")])));
+        assert!(privacy.requested(headers(&[("sec-gpc", "1")])));
+        assert!(!privacy.requested(headers(&[("dnt", "0")])));
+        assert!(!privacy.requested(headers(&[])));
+
+        let privacy = Privacy::default().with_signals("gpc").unwrap();
+        assert!(!privacy.requested(headers(&[("dnt", "1")])));
+        assert!(privacy.requested(headers(&[("sec-gpc", "1")])));
+
+        let disabled = Privacy::default().with_signals("").unwrap();
+        assert!(!disabled.requested(headers(&[("dnt", "1"), ("sec-gpc", "1")])));
+
+        assert!(Privacy::default().with_signals("dnt, lol").is_err());
+    }
+
+    #[test]
+    fn test_anonymize() {
+        assert_eq!(
+            Privacy::default().anonymize(event()).unwrap(),
+            json!({
+                "schema_version": SCHEMA_VERSION,
+                "event_id": "67e55044-10b1-426f-9247-bb680e5fe0c8",
+                "idempotency_key": "7a0b1c2d3e4f5a6b-FRA",
+                "timestamp": "2022-10-18T09:30:05.123Z",
+                "kind": "anonymous",
+                "request_kind": "media",
+                "upstream": "https://example.com/feed",
+                "upstream_ref": "https://example.com/podcast1.mp3",
+                "status": 302,
+                "client": "Overcast",
+                "category": "podcast-app",
+                "anonymous": true,
+                "count": 1,
+            })
+        );
+    }
+
+    #[test]
+    fn test_anonymize_custom_fields() {
+        assert_eq!(
+            Privacy::default()
+                .with_fields("kind, status, ip")
+                .anonymize(event())
+                .unwrap(),
+            json!({
+                "schema_version": SCHEMA_VERSION,
+                "event_id": "67e55044-10b1-426f-9247-bb680e5fe0c8",
+                "idempotency_key": "7a0b1c2d3e4f5a6b-FRA",
+                "kind": "anonymous",
+                "request_kind": "media",
+                "status": 302,
+                "anonymous": true,
+                "count": 1,
+            })
+        );
+        // The fields for deduplicating events are required
+        assert!(Privacy::default()
+            .anonymize(json!({ "kind": "media" }))
+            .is_err());
+    }
+
+    #[test]
+    fn test_idempotency_key() {
+        let event_id = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+        assert_eq!(
+            idempotency_key(Some("7a0b1c2d3e4f5a6b-FRA"), event_id),
+            "7a0b1c2d3e4f5a6b-FRA"
+        );
+        // Requests without `cf-ray` header fall back to the event ID
+        assert_eq!(idempotency_key(None, event_id), event_id);
+        assert_eq!(idempotency_key(Some(" "), event_id), event_id);
+    }
+
+    #[test]
+    fn test_is_conditional() {
+        assert!(is_conditional(headers(&[("if-none-match", "\"abc\"")])));
+        assert!(is_conditional(headers(&[(
+            "if-modified-since",
+            "Tue, 18 Oct 2022 09:30:05 GMT"
+        )])));
+        assert!(!is_conditional(headers(&[("user-agent", "Overcast/3.0")])));
+        assert!(!is_conditional(headers(&[])));
+    }
+
+    #[test]
+    fn test_kind_from_path() {
+        assert_eq!(Kind::from_path("/", "/r"), Some(Kind::Feed));
+        assert_eq!(Kind::from_path("/r/podcast1.mp3", "/r"), Some(Kind::Media));
+        assert_eq!(Kind::from_path("/admin/directories", "/r"), None);
+        assert_eq!(Kind::from_path("/rss", "/r"), None);
+    }
+
+    fn common() -> Common {
+        Common {
+            schema_version: SCHEMA_VERSION,
+            event_id: "67e55044-10b1-426f-9247-bb680e5fe0c8".to_string(),
+            idempotency_key: "7a0b1c2d3e4f5a6b-FRA".to_string(),
+            timestamp: "2022-10-18T09:30:05.123Z".to_string(),
+            upstream: "https://example.com/feed".to_string(),
+            method: "GET".to_string(),
+            status: 200,
+            conditional: false,
+            abusive: false,
+            client: "Overcast".to_string(),
+            category: ClientCategory::PodcastApp,
+            is_bot: false,
+            brand: None,
+            platform: None,
+            model: None,
+            mobile: None,
+            cloudflare: Cloudflare {
+                colo: Some("FRA".to_string()),
+                asn: Some(3320),
+                country: Some("DE".to_string()),
+                ..Cloudflare::default()
+            },
+            path: "/".to_string(),
+            headers: String::new(),
+            user_agent: Some("Overcast/3.0".to_string()),
+            origin: None,
+            referer: None,
+            ip: None,
+        }
+    }
+
+    #[test]
+    fn test_serialize() {
+        let event = serde_json::to_value(Event::MediaRequest(MediaRequestEvent {
+            common: common(),
+            upstream_ref: "https://example.com/podcast1.mp3".to_string(),
+            prefixes: vec!["podtrac".to_string()],
+            probe: true,
+        }))
+        .unwrap();
+        assert_eq!(event["schema_version"], json!(SCHEMA_VERSION));
+        assert_eq!(event["kind"], json!("media"));
+        assert_eq!(event["timestamp"], json!("2022-10-18T09:30:05.123Z"));
+        assert_eq!(event["category"], json!("podcast-app"));
+        assert_eq!(event["user_agent"], json!("Overcast/3.0"));
+        assert_eq!(
+            event["upstream_ref"],
+            json!("https://example.com/podcast1.mp3")
+        );
+        assert_eq!(
+            event["cloudflare"],
+            json!({ "colo": "FRA", "asn": 3320, "country": "DE" })
+        );
+        // The location is only part of the Cloudflare metadata
+        for field in ["country", "latitude", "longitude"] {
+            assert_eq!(event.get(field), None);
+        }
+
+        let event = serde_json::to_value(Event::FeedFetch(FeedFetchEvent {
+            common: common(),
+            subscribers: Some(42),
+        }))
+        .unwrap();
+        assert_eq!(event["kind"], json!("feed"));
+        assert_eq!(event["subscribers"], json!(42));
+        assert_eq!(event.get("upstream_ref"), None);
+    }
+
+    #[test]
+    fn test_typed_without_ref() {
+        let client = Client::new("Overcast", ClientCategory::PodcastApp);
+        let missing_ref = || Err(Error::RustError("Could not find ref parameter".to_string()));
+
+        // Feed fetches have no `ref`
+        let event = typed(Kind::Feed, common(), &client, missing_ref, false).unwrap();
+        assert!(matches!(event, Event::FeedFetch(_)));
+
+        // Media requests need one
+        assert!(typed(Kind::Media, common(), &client, missing_ref, false).is_err());
+        let event = typed(
+            Kind::Media,
+            common(),
+            &client,
+            || Ok(Url::parse("https://dts.podtrac.com/redirect.mp3/example.com/1.mp3").unwrap()),
+            true,
+        )
+        .unwrap();
+        let Event::MediaRequest(event) = event else {
+            panic!("expected media request event");
+        };
+        assert_eq!(event.upstream_ref, "https://example.com/1.mp3");
+        assert_eq!(event.prefixes, vec!["podtrac"]);
+        assert!(event.probe);
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema = serde_json::to_value(schema()).unwrap();
+        let kinds: Vec<&Value> = schema["oneOf"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|variant| {
+                assert!(variant["required"]
+                    .as_array()
+                    .unwrap()
+                    .contains(&json!("kind")));
+                &variant["properties"]["kind"]["enum"]
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![&json!(["feed"]), &json!(["media"]), &json!(["anonymous"])]
+        );
+        for name in ["Kind", "ClientCategory", "Cloudflare"] {
+            assert!(
+                schema["definitions"].get(name).is_some(),
+                "{name} missing in schema"
+            );
+        }
+    }
+
+    #[test]
+    fn test_events_match_schema() {
+        let schema = JSONSchema::compile(&serde_json::to_value(schema()).unwrap()).unwrap();
+        let client = Client::new("Feedly", ClientCategory::Aggregator).with_subscribers(Some(42));
+        let media_ref = || Ok(Url::parse("https://example.com/podcast1.mp3").unwrap());
+        let feed =
+            serde_json::to_value(typed(Kind::Feed, common(), &client, media_ref, false).unwrap())
+                .unwrap();
+        let media =
+            serde_json::to_value(typed(Kind::Media, common(), &client, media_ref, true).unwrap())
+                .unwrap();
+        let all_fields = Privacy::default().with_fields(&ANONYMOUS_FIELDS.join(", "));
+        let mut events = vec![feed.clone(), media.clone()];
+        for event in [feed.clone(), media.clone()] {
+            for privacy in [
+                Privacy::default(),
+                Privacy::default().with_fields(""),
+                all_fields.clone(),
+            ] {
+                events.push(privacy.anonymize(event.clone()).unwrap());
+            }
+        }
+        for event in &events {
+            assert!(schema.is_valid(event), "{event} doesn't match the schema");
+        }
+
+        // The kind decides which fields are required
+        let mut feed_as_media = feed;
+        feed_as_media["kind"] = json!("media");
+        assert!(!schema.is_valid(&feed_as_media));
+        let mut without_kind = media;
+        without_kind.as_object_mut().unwrap().remove("kind");
+        assert!(!schema.is_valid(&without_kind));
+        assert!(!schema.is_valid(&json!({ "kind": "anonymous", "anonymous": true })));
+    }
+}
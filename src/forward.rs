@@ -1,4 +1,5 @@
-use urlencoding::decode;
+use crate::codec::{self, TokenKey};
+use crate::headers::MAX_HOPS;
 use worker::{Error, Request, Result, Url};
 
 /// Check if the given request URL points to a valid mp3 file
@@ -15,29 +16,34 @@ fn valid_forwarding_url(request: &Request, prefix: &str) -> Result<()> {
         return Err(Error::RustError(format!(
             "Unknown audio file format: {path}"
         )));
-    };
+    }
     Ok(())
 }
 
-/// Extract redirect URL from ref parameter
-pub fn extract_ref(request: &Request) -> Result<Url> {
-    let html_decoded = html_escape::decode_html_entities(&request.url()?).to_string();
-    let url = Url::parse(&html_decoded)?;
-    if let Some((_, forward)) = url.query_pairs().find(|(k, _)| k == "ref") {
-        let decoded = decode(&forward)
-            .map_err(|e| Error::RustError(format!("Cannot decode ref {forward}: {e}")))?;
-        return Ok(Url::parse(&decoded)?);
-    };
-    Err(Error::RustError("Could not find ref parameter".to_string()))
+/// Extract redirect URL from ref parameter, token or path
+pub fn extract_ref(request: &Request, prefix: Option<&str>, key: Option<&TokenKey>) -> Result<Url> {
+    codec::decode(request.url()?.as_str(), prefix, key)
 }
 
 /// Extract our custom forward URL form the request.
-/// It is encoded in the `ref` query parameter
+/// It is encoded in the `ref` query parameter, the `fwd` token parameter or
+/// the path
 /// Example:
 /// <https://example.org/r/podcast1.mp3?ref=https%253A%252F%252Fexample.com%252Fpodcast1.mp3>
-pub fn get(request: &Request, prefix: Option<&str>) -> Result<Url> {
+/// <https://example.org/r/podcast1.mp3?fwd=Acl3u-UFkQPzN35bsuSL…>
+/// <https://example.org/r/https/example.com/podcast1.mp3>
+///
+/// Upstream URLs pointing back at the forwarder get unwrapped, so clients
+/// don't end up in a redirect loop.
+pub fn get(request: &Request, prefix: Option<&str>, key: Option<&TokenKey>) -> Result<Url> {
     if let Some(prefix) = prefix {
         valid_forwarding_url(request, prefix)?;
     }
-    extract_ref(request)
+    codec::unwrap_nested(
+        extract_ref(request, prefix, key)?,
+        &request.url()?,
+        prefix,
+        key,
+        MAX_HOPS,
+    )
 }
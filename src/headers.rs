@@ -0,0 +1,275 @@
+//! Header policy for the feed routes
+//!
+//! The rewritten feed differs from the upstream feed, so upstream headers
+//! describing the body (`Content-Length`, `Content-Encoding`, `ETag`) are
+//! wrong for our response. Instead of copying headers wholesale, only
+//! allowlisted headers are passed on in either direction and the body-specific
+//! headers get recomputed for the rewritten feed.
+//!
+//! `HEAD` requests only send a `HEAD` request upstream, so the body-specific
+//! headers are taken from the last rewrite of the same upstream version.
+
+use lru::LruCache;
+use std::num::NonZeroUsize;
+use std::sync::{LazyLock, Mutex};
+
+/// Request headers passed on to the upstream feed.
+/// `If-None-Match` is missing on purpose: clients only know our `ETag`, so it
+/// is evaluated by the forwarder itself.
+pub const UPSTREAM_REQUEST_HEADERS: &[&str] = &[
+    "accept",
+    "accept-language",
+    "if-modified-since",
+    "user-agent",
+];
+
+/// Upstream response headers passed on to the client
+pub const FEED_RESPONSE_HEADERS: &[&str] = &[
+    "cache-control",
+    "content-language",
+    "content-type",
+    "expires",
+    "last-modified",
+];
+
+/// Content type for feeds if the upstream didn't send one
+pub const DEFAULT_CONTENT_TYPE: &str = "application/rss+xml; charset=utf-8";
+
+/// Keep only the headers on the given allowlist. Header names are compared
+/// case-insensitively.
+pub fn allowed<I>(headers: I, allowlist: &[&str]) -> Vec<(String, String)>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    headers
+        .into_iter()
+        .filter(|(name, _)| {
+            allowlist
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(name))
+        })
+        .collect()
+}
+
+/// Request header counting how often a feed request passed through a
+/// forwarder. It protects against fetch loops, e.g. if the upstream feed URL
+/// points at the forwarder itself or at another forwarder pointing back.
+pub const HOPS_HEADER: &str = "x-forwarder-hops";
+
+/// Maximum number of forwarders a request may pass through
+pub const MAX_HOPS: u32 = 3;
+
+/// Parse the value of the hop-count header. Missing or invalid values count
+/// as zero hops.
+#[must_use]
+pub fn hops(value: Option<&str>) -> u32 {
+    value.and_then(|hops| hops.trim().parse().ok()).unwrap_or(0)
+}
+
+/// Byte ranges up to this length are probes of podcast apps, e.g. for
+/// checking if the file exists, and not downloads
+pub const PROBE_RANGE_MAX_BYTES: u64 = 2;
+
+/// Check if a `Range` header value requests only a tiny part of the file,
+/// like `bytes=0-1` or `bytes=0-0`
+///
+/// Open-ended ranges (`bytes=0-`), suffix ranges (`bytes=-500`) and multiple
+/// ranges are never probes.
+#[must_use]
+pub fn is_probe_range(range: &str) -> bool {
+    let Some((start, end)) = range
+        .trim()
+        .strip_prefix("bytes=")
+        .and_then(|range| range.split_once('-'))
+    else {
+        return false;
+    };
+    match (start.trim().parse::<u64>(), end.trim().parse::<u64>()) {
+        (Ok(start), Ok(end)) => end >= start && end - start < PROBE_RANGE_MAX_BYTES,
+        _ => false,
+    }
+}
+
+/// Compute a strong `ETag` for the given body
+///
+/// Uses 64-bit FNV-1a, which is fast and stable across deployments. It is not
+/// meant to be cryptographically secure.
+#[must_use]
+pub fn etag(body: &str) -> String {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+    let hash = body.bytes().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+    });
+    format!("\"{:x}-{hash:016x}\"", body.len())
+}
+
+/// Check if an `If-None-Match` header value matches the given `ETag`
+///
+/// Uses the weak comparison required for `If-None-Match`, so `W/"abc"`
+/// matches `"abc"`.
+#[must_use]
+pub fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let strip_weak = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = strip_weak(etag);
+    if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || strip_weak(tag) == etag)
+}
+
+/// Maximum number of remembered rewritten feeds
+const REWRITTEN_CACHE_SIZE: usize = 64;
+
+/// Body-specific headers of a rewritten feed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rewritten {
+    /// `ETag` of the rewritten feed
+    pub etag: String,
+    /// Length of the rewritten feed in bytes
+    pub length: usize,
+}
+
+/// Rewritten feeds of the requests handled by this isolate. The rewrite
+/// depends on the upstream version, the client (see `FEED_RULES`) and the
+/// request URL, so all of them are part of the key.
+static REWRITTEN: LazyLock<Mutex<LruCache<String, Rewritten>>> = LazyLock::new(|| {
+    Mutex::new(LruCache::new(
+        NonZeroUsize::new(REWRITTEN_CACHE_SIZE).unwrap(),
+    ))
+});
+
+/// Cache key of a rewritten feed. `version` is the `ETag` or `Last-Modified`
+/// header of the upstream feed.
+fn rewritten_key(version: &str, client: &str, url: &str) -> String {
+    format!("{version}\n{client}\n{url}")
+}
+
+/// Remember the headers of a rewritten feed for later `HEAD` requests
+pub fn remember(version: &str, client: &str, url: &str, rewritten: Rewritten) {
+    REWRITTEN
+        .lock()
+        .unwrap()
+        .put(rewritten_key(version, client, url), rewritten);
+}
+
+/// Headers of the last rewrite of the given upstream version for the client
+/// and request URL, if it is still remembered
+pub fn rewritten(version: &str, client: &str, url: &str) -> Option<Rewritten> {
+    REWRITTEN
+        .lock()
+        .unwrap()
+        .get(&rewritten_key(version, client, url))
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    fn pairs(headers: &[(&str, &str)]) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .map(|(k, v)| ((*k).to_string(), (*v).to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_allowed_response_headers() {
+        let upstream = pairs(&[
+            ("Content-Type", "application/rss+xml"),
+            ("Content-Length", "1234"),
+            ("Content-Encoding", "gzip"),
+            ("Set-Cookie", "session=secret"),
+            ("ETag", "\"upstream\""),
+            ("Connection", "keep-alive"),
+            ("Transfer-Encoding", "chunked"),
+            ("Last-Modified", "Sat, 05 Feb 2022 02:13:19 GMT"),
+            ("cache-control", "max-age=600"),
+        ]);
+        assert_eq!(
+            allowed(upstream, FEED_RESPONSE_HEADERS),
+            pairs(&[
+                ("Content-Type", "application/rss+xml"),
+                ("Last-Modified", "Sat, 05 Feb 2022 02:13:19 GMT"),
+                ("cache-control", "max-age=600"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_allowed_request_headers() {
+        let request = pairs(&[
+            ("host", "forwarder.example.com"),
+            ("cookie", "forwarder=bar"),
+            ("user-agent", "Spotify/1.0"),
+            ("if-none-match", "\"abc\""),
+            ("if-modified-since", "Sat, 05 Feb 2022 02:13:19 GMT"),
+            ("x-real-ip", "127.0.0.1"),
+        ]);
+        assert_eq!(
+            allowed(request, UPSTREAM_REQUEST_HEADERS),
+            pairs(&[
+                ("user-agent", "Spotify/1.0"),
+                ("if-modified-since", "Sat, 05 Feb 2022 02:13:19 GMT"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_hops() {
+        assert_eq!(hops(None), 0);
+        assert_eq!(hops(Some("2")), 2);
+        assert_eq!(hops(Some(" 1 ")), 1);
+        assert_eq!(hops(Some("lol")), 0);
+    }
+
+    #[test]
+    fn test_is_probe_range() {
+        assert!(is_probe_range("bytes=0-1"));
+        assert!(is_probe_range("bytes=0-0"));
+        assert!(is_probe_range(" bytes=1000-1001"));
+        assert!(!is_probe_range("bytes=0-"));
+        assert!(!is_probe_range("bytes=0-2"));
+        assert!(!is_probe_range("bytes=-1"));
+        assert!(!is_probe_range("bytes=0-1, 5-6"));
+        assert!(!is_probe_range("bytes=1-0"));
+        assert!(!is_probe_range("items=0-1"));
+    }
+
+    #[test]
+    fn test_etag() {
+        assert_eq!(etag(""), "\"0-cbf29ce484222325\"");
+        assert_eq!(etag("<rss/>"), etag("<rss/>"));
+        assert_ne!(etag("<rss/>"), etag("<rss />"));
+    }
+
+    #[test]
+    fn test_etag_matches() {
+        let tag = etag("<rss/>");
+        assert!(etag_matches(&tag, &tag));
+        assert!(etag_matches(&format!("W/{tag}"), &tag));
+        assert!(etag_matches(&format!("\"other\", {tag}"), &tag));
+        assert!(etag_matches("*", &tag));
+        assert!(!etag_matches("\"other\"", &tag));
+    }
+
+    #[test]
+    fn test_rewritten() {
+        let expected = Rewritten {
+            etag: etag("<rss/>"),
+            length: 6,
+        };
+        let version = "\"upstream-1\"";
+        let url = "https://forwarder.dev/";
+        remember(version, "Overcast", url, expected.clone());
+        assert_eq!(rewritten(version, "Overcast", url), Some(expected));
+        assert_eq!(rewritten(version, "Spotify", url), None);
+        assert_eq!(rewritten("\"upstream-2\"", "Overcast", url), None);
+        assert_eq!(
+            rewritten(version, "Overcast", "https://forwarder.dev/?a=1"),
+            None
+        );
+    }
+}
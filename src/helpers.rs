@@ -1,4 +1,15 @@
-use worker::{console_log, Date, Request, Result, RouteContext};
+use crate::cloudflare::Fields;
+use crate::codec::{Mode, TokenKey};
+use crate::cors::Cors;
+use crate::event::Privacy;
+use crate::ip::{self, TrustedProxies};
+use crate::prefix::Policy;
+use crate::ratelimit::{Action, Limit, Route};
+use crate::rules::Rules;
+use std::net::IpAddr;
+use std::sync::Arc;
+use url::Url;
+use worker::{console_log, Date, Env, Error, Request, Result, RouteContext};
 
 /// Log request information
 pub fn log_request(req: &Request) {
@@ -20,3 +31,213 @@ pub fn upstream<D>(ctx: &RouteContext<D>) -> Result<String> {
 pub fn website<D>(ctx: &RouteContext<D>) -> Result<String> {
     Ok(ctx.var("WEBSITE_URL")?.to_string())
 }
+
+/// Default path prefix of the forwarding route
+const DEFAULT_ROUTE_PREFIX: &str = "/r";
+
+/// Normalize the slashes of a route prefix, e.g. `r/` becomes `/r`.
+/// Defaults to `/r` for an empty prefix
+fn normalize_route_prefix(prefix: &str) -> String {
+    let prefix = prefix.trim().trim_matches('/');
+    if prefix.is_empty() {
+        DEFAULT_ROUTE_PREFIX.to_string()
+    } else {
+        format!("/{prefix}")
+    }
+}
+
+/// Get the path prefix of the forwarding route from the worker environment
+/// Defaults to `/r` if not set
+pub fn env_route_prefix(env: &Env) -> String {
+    env.var("ROUTE_PREFIX").map_or_else(
+        |_| DEFAULT_ROUTE_PREFIX.to_string(),
+        |prefix| normalize_route_prefix(&prefix.to_string()),
+    )
+}
+
+/// Get the path prefix of the forwarding route from the worker config
+/// Defaults to `/r` if not set
+pub fn route_prefix<D>(ctx: &RouteContext<D>) -> String {
+    env_route_prefix(&ctx.env)
+}
+
+/// Get the CORS configuration from the worker config
+/// CORS is disabled if `CORS_ALLOWED_ORIGINS` is not set
+pub fn cors<D>(ctx: &RouteContext<D>) -> Cors {
+    let origins = ctx
+        .var("CORS_ALLOWED_ORIGINS")
+        .map(|origins| origins.to_string())
+        .unwrap_or_default();
+    let mut cors = Cors::new(&origins);
+    if let Ok(methods) = ctx.var("CORS_ALLOWED_METHODS") {
+        cors = cors.with_methods(&methods.to_string());
+    }
+    if let Ok(expose_headers) = ctx.var("CORS_EXPOSE_HEADERS") {
+        cors = cors.with_expose_headers(&expose_headers.to_string());
+    }
+    cors
+}
+
+/// Get the networks of trusted proxies in front of the forwarder from the
+/// worker config. Defaults to no trusted proxies if not set
+pub fn trusted_proxies<D>(ctx: &RouteContext<D>) -> Result<TrustedProxies> {
+    ctx.var("TRUSTED_PROXIES").map_or_else(
+        |_| Ok(TrustedProxies::default()),
+        |proxies| proxies.to_string().parse(),
+    )
+}
+
+/// Resolve the IP of the client, taking trusted proxies into account
+///
+/// Falls back to `X-Real-IP` if the request didn't pass Cloudflare, e.g.
+/// in local development.
+pub fn client_ip<D>(request: &Request, ctx: &RouteContext<D>) -> Result<Option<IpAddr>> {
+    let headers = request.headers();
+    let peer = match headers.get("cf-connecting-ip")? {
+        Some(peer) => Some(peer),
+        None => headers.get("x-real-ip")?,
+    };
+    Ok(ip::resolve(
+        peer.as_deref(),
+        headers.get("forwarded")?.as_deref(),
+        headers.get("x-forwarded-for")?.as_deref(),
+        &trusted_proxies(ctx)?,
+    ))
+}
+
+/// Get the privacy mode for listeners sending `DNT` or `Sec-GPC` from the
+/// worker config. Defaults to honoring both signals
+pub fn privacy<D>(ctx: &RouteContext<D>) -> Result<Privacy> {
+    let mut privacy = Privacy::default();
+    if let Ok(signals) = ctx.var("PRIVACY_SIGNALS") {
+        privacy = privacy.with_signals(&signals.to_string())?;
+    }
+    if let Ok(fields) = ctx.var("ANONYMOUS_EVENT_FIELDS") {
+        privacy = privacy.with_fields(&fields.to_string());
+    }
+    Ok(privacy)
+}
+
+/// Get the Cloudflare fields captured in events from the worker config.
+/// `CLOUDFLARE_FIELDS` selects the fields (all if not set),
+/// `CLOUDFLARE_FIELDS_DENY` turns off fields
+pub fn cloudflare_fields<D>(ctx: &RouteContext<D>) -> Result<Fields> {
+    let mut fields = Fields::default();
+    if let Ok(allow) = ctx.var("CLOUDFLARE_FIELDS") {
+        fields = fields.with_allow(&allow.to_string())?;
+    }
+    if let Ok(deny) = ctx.var("CLOUDFLARE_FIELDS_DENY") {
+        fields = fields.with_deny(&deny.to_string())?;
+    }
+    Ok(fields)
+}
+
+/// Get the rate limit of the route from the worker config, e.g.
+/// `RATE_LIMIT_MEDIA = "30/minute"`. Routes without a limit are not limited
+pub fn rate_limit<D>(ctx: &RouteContext<D>, route: Route) -> Result<Option<Limit>> {
+    let var = match route {
+        Route::Feed => "RATE_LIMIT_FEED",
+        Route::Media => "RATE_LIMIT_MEDIA",
+    };
+    ctx.var(var)
+        .map_or(Ok(None), |limit| limit.to_string().parse().map(Some))
+}
+
+/// Get what happens to requests exceeding the rate limit from the worker
+/// config. Defaults to marking their events as abusive
+pub fn rate_limit_action<D>(ctx: &RouteContext<D>) -> Result<Action> {
+    ctx.var("RATE_LIMIT_ACTION").map_or_else(
+        |_| Ok(Action::default()),
+        |action| action.to_string().parse(),
+    )
+}
+
+/// Get the token for the admin endpoints from the worker secrets
+/// The admin endpoints are disabled if it is not set
+pub fn admin_token<D>(ctx: &RouteContext<D>) -> Option<String> {
+    ctx.secret("ADMIN_TOKEN")
+        .ok()
+        .map(|token| token.to_string())
+}
+
+/// Get the format of forwarding URLs from the worker config
+/// Defaults to `ref` if not set. The `token` format requires `TOKEN_SECRET`
+pub fn forward_url_mode<D>(ctx: &RouteContext<D>) -> Result<Mode> {
+    let mode = ctx
+        .var("FORWARD_URL_MODE")
+        .map_or_else(|_| Ok(Mode::default()), |mode| mode.to_string().parse())?;
+    if mode == Mode::Token && token_key(ctx).is_none() {
+        return Err(Error::RustError(
+            "FORWARD_URL_MODE `token` requires the TOKEN_SECRET secret".to_string(),
+        ));
+    }
+    Ok(mode)
+}
+
+/// Get the key for sealing forwarding tokens from the worker secrets
+/// Tokens can't be created or opened if `TOKEN_SECRET` is not set
+pub fn token_key<D>(ctx: &RouteContext<D>) -> Option<TokenKey> {
+    ctx.secret("TOKEN_SECRET")
+        .ok()
+        .map(|secret| TokenKey::new(&secret.to_string()))
+}
+
+/// Get the policy for third-party measurement prefixes of upstream URLs
+/// from the worker config. Defaults to `keep` if not set
+pub fn measurement_prefixes<D>(ctx: &RouteContext<D>) -> Result<Policy> {
+    ctx.var("MEASUREMENT_PREFIXES").map_or_else(
+        |_| Ok(Policy::default()),
+        |policy| policy.to_string().parse(),
+    )
+}
+
+/// Get the per-client feed customization rules from the worker config
+/// Defaults to no rules if not set. The rules are only compiled again if the
+/// config changes
+pub fn rules<D>(ctx: &RouteContext<D>) -> Result<Arc<Rules>> {
+    ctx.var("FEED_RULES").map_or_else(
+        |_| Ok(Arc::default()),
+        |rules| Rules::cached(&rules.to_string()),
+    )
+}
+
+/// Get the optional public URL of the forwarded feed from the worker config
+pub fn feed_url<D>(ctx: &RouteContext<D>) -> Result<Option<Url>> {
+    match ctx.var("FEED_URL") {
+        Ok(url) => Ok(Some(Url::parse(&url.to_string())?)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Check if subscribers should be migrated to the forwarder with an
+/// `<itunes:new-feed-url>` element
+pub fn new_feed_url<D>(ctx: &RouteContext<D>) -> bool {
+    ctx.var("ITUNES_NEW_FEED_URL")
+        .is_ok_and(|enabled| enabled.to_string() == "true")
+}
+
+/// Get the optional template for item-level `<link>` elements from the worker
+/// config, e.g. `{website}/episodes/{guid}`
+pub fn item_link_template<D>(ctx: &RouteContext<D>) -> Option<String> {
+    ctx.var("ITEM_LINK_TEMPLATE")
+        .ok()
+        .map(|template| template.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_normalize_route_prefix() {
+        assert_eq!(normalize_route_prefix("/r"), "/r");
+        assert_eq!(normalize_route_prefix("/r/"), "/r");
+        assert_eq!(normalize_route_prefix("r"), "/r");
+        assert_eq!(normalize_route_prefix("//media//"), "/media");
+        assert_eq!(normalize_route_prefix("/media/v1/"), "/media/v1");
+        assert_eq!(normalize_route_prefix("/"), DEFAULT_ROUTE_PREFIX);
+        assert_eq!(normalize_route_prefix(""), DEFAULT_ROUTE_PREFIX);
+    }
+}
@@ -0,0 +1,215 @@
+//! Client IP resolution behind trusted proxies
+//!
+//! Cloudflare passes the IP of the connecting client in `CF-Connecting-IP`.
+//! If that is one of our own proxies, the original client IP has to be taken
+//! from `Forwarded` or `X-Forwarded-For`. These headers can be set by anyone,
+//! so they are only followed from right to left as long as every hop is a
+//! trusted proxy.
+
+use ipnet::IpNet;
+use std::net::IpAddr;
+use std::str::FromStr;
+use worker::{Error, Result};
+
+/// Networks of proxies in front of the forwarder
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    /// Check if the given IP belongs to a trusted proxy
+    #[must_use]
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.iter().any(|net| net.contains(ip))
+    }
+}
+
+impl FromStr for TrustedProxies {
+    type Err = Error;
+
+    /// Parse a comma-separated list of networks or single IPs, e.g.
+    /// `10.0.0.0/8, 2001:db8::1`
+    fn from_str(s: &str) -> Result<Self> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|net| !net.is_empty())
+            .map(|net| {
+                net.parse::<IpNet>()
+                    .or_else(|_| net.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|e| Error::RustError(format!("Invalid trusted proxy {net}: {e}")))
+            })
+            .collect::<Result<_>>()
+            .map(Self)
+    }
+}
+
+/// Parse an IP address from a header value
+///
+/// Supports quoted values, bracketed IPv6 addresses and ports as used in
+/// `Forwarded`, e.g. `"[2001:db8::1]:4711"` or `192.0.2.1:80`.
+fn parse_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    if let Some(bracketed) = value.strip_prefix('[') {
+        return bracketed.split_once(']')?.0.parse().ok();
+    }
+    value.parse().ok().or_else(|| {
+        // IPv4 with port, IPv6 addresses with ports have to be bracketed
+        let (ip, _port) = value.split_once(':')?;
+        ip.parse::<std::net::Ipv4Addr>().ok().map(IpAddr::V4)
+    })
+}
+
+/// IPs of the `for` parameters of a `Forwarded` header in order.
+/// Invalid or obfuscated identifiers like `unknown` are `None`.
+fn forwarded_for(value: &str) -> Vec<Option<IpAddr>> {
+    value
+        .split(',')
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                key.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| parse_ip(value))
+            })
+        })
+        .collect()
+}
+
+/// IPs of an `X-Forwarded-For` header in order
+fn x_forwarded_for(value: &str) -> Vec<Option<IpAddr>> {
+    value.split(',').map(parse_ip).collect()
+}
+
+/// Resolve the IP of the client
+///
+/// `peer` is the IP which connected to Cloudflare. If it is a trusted proxy,
+/// the hops of `Forwarded` (or `X-Forwarded-For` if there is no `Forwarded`
+/// header) are walked from right to left until the first untrusted IP. An
+/// invalid hop stops the walk at the last valid one, because everything to
+/// its left can't be verified.
+#[must_use]
+pub fn resolve(
+    peer: Option<&str>,
+    forwarded: Option<&str>,
+    x_forwarded: Option<&str>,
+    trusted: &TrustedProxies,
+) -> Option<IpAddr> {
+    let mut client = parse_ip(peer?)?;
+    if !trusted.contains(&client) {
+        return Some(client);
+    }
+    let hops = forwarded
+        .map(forwarded_for)
+        .or_else(|| x_forwarded.map(x_forwarded_for))
+        .unwrap_or_default();
+    for hop in hops.into_iter().rev() {
+        let Some(hop) = hop else {
+            break;
+        };
+        client = hop;
+        if !trusted.contains(&client) {
+            break;
+        }
+    }
+    Some(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    fn trusted() -> TrustedProxies {
+        "10.0.0.0/8, 2001:db8::1".parse().unwrap()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_trusted_proxies_from_str() {
+        let trusted = trusted();
+        assert!(trusted.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(trusted.contains(&"2001:db8::1".parse().unwrap()));
+        assert!(!trusted.contains(&"2001:db8::2".parse().unwrap()));
+        assert_eq!(
+            "".parse::<TrustedProxies>().unwrap(),
+            TrustedProxies::default()
+        );
+        assert!("10.0.0.0/33".parse::<TrustedProxies>().is_err());
+        assert!("lol".parse::<TrustedProxies>().is_err());
+    }
+
+    #[test]
+    fn test_parse_ip() {
+        assert_eq!(parse_ip(" 192.0.2.1 "), Some(ip("192.0.2.1")));
+        assert_eq!(parse_ip("192.0.2.1:80"), Some(ip("192.0.2.1")));
+        assert_eq!(parse_ip("2001:db8::2"), Some(ip("2001:db8::2")));
+        assert_eq!(parse_ip("\"[2001:db8::2]:4711\""), Some(ip("2001:db8::2")));
+        assert_eq!(parse_ip("unknown"), None);
+        assert_eq!(parse_ip("_hidden"), None);
+        assert_eq!(parse_ip("999.0.2.1"), None);
+    }
+
+    #[test]
+    fn test_untrusted_peer() {
+        assert_eq!(
+            resolve(Some("192.0.2.1"), None, Some("198.51.100.1"), &trusted()),
+            Some(ip("192.0.2.1"))
+        );
+        assert_eq!(resolve(None, None, Some("198.51.100.1"), &trusted()), None);
+        assert_eq!(resolve(Some("lol"), None, None, &trusted()), None);
+    }
+
+    #[test]
+    fn test_x_forwarded_for() {
+        // Spoofed first hop gets ignored
+        assert_eq!(
+            resolve(
+                Some("10.0.0.1"),
+                None,
+                Some("203.0.113.9, 198.51.100.1, 10.0.0.2"),
+                &trusted()
+            ),
+            Some(ip("198.51.100.1"))
+        );
+        // Only trusted hops
+        assert_eq!(
+            resolve(Some("10.0.0.1"), None, Some("10.0.0.2"), &trusted()),
+            Some(ip("10.0.0.2"))
+        );
+        // Invalid hop stops the walk
+        assert_eq!(
+            resolve(
+                Some("10.0.0.1"),
+                None,
+                Some("198.51.100.1, lol"),
+                &trusted()
+            ),
+            Some(ip("10.0.0.1"))
+        );
+    }
+
+    #[test]
+    fn test_forwarded() {
+        assert_eq!(
+            resolve(
+                Some("2001:db8::1"),
+                Some(r#"for=192.0.2.60;proto=http, For="[2001:db8:cafe::17]:4711""#),
+                Some("203.0.113.9"),
+                &trusted()
+            ),
+            Some(ip("2001:db8:cafe::17"))
+        );
+        assert_eq!(
+            resolve(
+                Some("10.0.0.1"),
+                Some("for=198.51.100.1, for=unknown"),
+                None,
+                &trusted()
+            ),
+            Some(ip("10.0.0.1"))
+        );
+    }
+}
@@ -6,20 +6,468 @@
 #![warn(clippy::nursery)]
 #![warn(clippy::cargo)]
 #![allow(clippy::future_not_send)]
+#![allow(clippy::multiple_crate_versions)]
 
+mod admin;
 mod client;
+mod cloudflare;
+mod codec;
+mod cors;
+mod directory;
+mod durable;
 mod event;
 mod forward;
+mod headers;
 mod helpers;
+mod ip;
 mod openpodcast;
 mod panic;
+mod prefix;
+mod ratelimit;
 mod rss;
+mod rules;
+mod subscribers;
+mod time;
+mod unknown;
+mod validate;
+
+pub use directory::Directories;
+pub use ratelimit::RateLimits;
+pub use subscribers::Subscribers;
+pub use unknown::UnknownUserAgents;
 
 use crate::{helpers::website, rss::Replacer};
-use client::client;
-use helpers::{log_request, upstream};
+use client::{client, Client, ClientCategory};
+use helpers::{
+    admin_token, client_ip, cors, env_route_prefix, feed_url, forward_url_mode, item_link_template,
+    log_request, measurement_prefixes, new_feed_url, rate_limit_action, route_prefix, rules,
+    token_key, upstream,
+};
+use ratelimit::{Action, Route, Verdict};
 use url::Url;
-use worker::{console_log, event, Env, Fetch, Method, Request, Response, Result, Router};
+use worker::{
+    console_error, console_log, event, Context, Date, Env, Error, Fetch, Headers, Method, Request,
+    Response, Result, RouteContext, Router,
+};
+
+/// Check the rate limit of the route for the request
+///
+/// A failing rate limit only gets logged and the request is allowed, so the
+/// limiter never blocks feed delivery or playback.
+async fn rate_limit(request: &Request, ctx: &RouteContext<Context>, route: Route) -> Verdict {
+    match check_rate_limit(request, ctx, route).await {
+        Ok(verdict) => verdict,
+        Err(e) => {
+            console_error!("Cannot check rate limit: {e}");
+            Verdict::Allow
+        }
+    }
+}
+
+/// Check the rate limit of the route for the request, see `rate_limit`
+async fn check_rate_limit(
+    request: &Request,
+    ctx: &RouteContext<Context>,
+    route: Route,
+) -> Result<Verdict> {
+    let Some(limit) = helpers::rate_limit(ctx, route)? else {
+        return Ok(Verdict::Allow);
+    };
+    let key = ratelimit::key(
+        route,
+        client_ip(request, ctx)?,
+        request.headers().get("user-agent")?.as_deref(),
+    );
+    ratelimit::check(&ctx.env, &key, limit, Date::now().as_millis()).await
+}
+
+/// Answer with `429 Too Many Requests` if the request exceeds the limit and
+/// such requests get rejected
+fn reject(verdict: Verdict, ctx: &RouteContext<Context>) -> Result<Option<Response>> {
+    match verdict {
+        Verdict::Limited { retry_after } if rate_limit_action(ctx)? == Action::Reject => {
+            let mut headers = Headers::new();
+            headers.set("Retry-After", &retry_after.to_string())?;
+            Ok(Some(
+                Response::error("Too Many Requests", 429)?.with_headers(headers),
+            ))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Send the event for the request received at `received` (in milliseconds).
+/// Events of requests exceeding the rate limit get marked as abusive or
+/// dropped.
+///
+/// The event is sent after the response went out, so a slow `OpenPodcast API`
+/// doesn't delay the response. Failed deliveries only get logged. Unknown
+/// user agents get counted the same way, see `admin::unknown_user_agents`.
+fn track(
+    request: &Request,
+    ctx: &RouteContext<Context>,
+    client: &Client,
+    received: u64,
+    status: u16,
+    verdict: Verdict,
+) -> Result<()> {
+    if client.is_unknown() {
+        if let Some(user_agent) = request.headers().get("user-agent")? {
+            // `Env` is a handle of a JavaScript object, the clone refers to the same bindings
+            let env: Env = ctx.env.clone().into();
+            ctx.data.wait_until(async move {
+                if let Err(e) = unknown::record(&env, &user_agent).await {
+                    console_error!("Cannot count unknown user agent: {e}");
+                }
+            });
+        }
+    }
+    let abusive = verdict != Verdict::Allow;
+    if abusive && rate_limit_action(ctx)? == Action::Drop {
+        return Ok(());
+    }
+    let delivery = event::Delivery::new(request, ctx, client, received, status, abusive)?;
+    ctx.data.wait_until(async move {
+        if let Err(e) = delivery.send().await {
+            console_error!("Cannot send event: {e}");
+        }
+    });
+    Ok(())
+}
+
+/// Answer feed requests which would loop or are blocked by the rules, so
+/// they don't reach the upstream server. Admitted requests are counted for
+/// the subscriber estimates.
+fn admit(
+    request: &Request,
+    ctx: &RouteContext<Context>,
+    client: &Client,
+) -> Result<Option<Response>> {
+    console_log!("Received request from {}", client.name());
+
+    // Protect against fetching our own feed, directly or through other
+    // forwarders
+    let upstream = &upstream(ctx)?;
+    let hops = headers::hops(request.headers().get(headers::HOPS_HEADER)?.as_deref());
+    if hops >= headers::MAX_HOPS || Url::parse(upstream)?.origin() == request.url()?.origin() {
+        console_error!("Loop detected for upstream {upstream} after {hops} hop(s)");
+        return Ok(Some(Response::error("Loop Detected", 508)?));
+    }
+    if rules(ctx)?.blocks(client) {
+        return Ok(Some(Response::error("Forbidden", 403)?));
+    }
+    if let Some(fetch) =
+        subscribers::Fetch::new(client, client_ip(request, ctx)?, Date::now().as_millis())
+    {
+        let env: Env = ctx.env.clone().into();
+        ctx.data.wait_until(async move {
+            if let Err(e) = subscribers::record(&env, fetch).await {
+                console_error!("Cannot count subscriber: {e}");
+            }
+        });
+    }
+    Ok(None)
+}
+
+/// Fetch the upstream feed with the given method
+///
+/// Only allowlisted headers are passed on, e.g. `If-Modified-Since`, so
+/// unchanged feeds get answered with `304`.
+async fn fetch_upstream(
+    request: &Request,
+    ctx: &RouteContext<Context>,
+    method: Method,
+) -> Result<Response> {
+    let hops = headers::hops(request.headers().get(headers::HOPS_HEADER)?.as_deref());
+    let mut req = Request::new(&upstream(ctx)?, method)?;
+    let upstream_headers = req.headers_mut()?;
+    for (key, value) in headers::allowed(request.headers(), headers::UPSTREAM_REQUEST_HEADERS) {
+        upstream_headers.set(&key, &value)?;
+    }
+    upstream_headers.set(headers::HOPS_HEADER, &(hops + 1).to_string())?;
+    Fetch::Request(req).send().await
+}
+
+/// Headers of our feed response which are taken from the upstream response
+fn feed_headers(upstream: &Response) -> Result<Headers> {
+    let mut headers = Headers::new();
+    for (key, value) in headers::allowed(upstream.headers(), headers::FEED_RESPONSE_HEADERS) {
+        headers.set(&key, &value)?;
+    }
+    Ok(headers)
+}
+
+/// Version of the upstream feed, i.e. its `ETag` or `Last-Modified` header
+fn upstream_version(upstream: &Response) -> Result<Option<String>> {
+    let etag = upstream.headers().get("etag")?;
+    if etag.is_some() {
+        return Ok(etag);
+    }
+    upstream.headers().get("last-modified")
+}
+
+/// Set the headers of every feed response with a body
+fn set_feed_headers(headers: &mut Headers, rewritten: &headers::Rewritten) -> Result<()> {
+    if !headers.has("content-type")? {
+        headers.set("Content-Type", headers::DEFAULT_CONTENT_TYPE)?;
+    }
+    headers.set("Content-Length", &rewritten.length.to_string())?;
+    // Ask browsers for client hints, so web players can be told apart
+    headers.set("Accept-CH", client::ACCEPT_CH)?;
+    headers.set("ETag", &rewritten.etag)?;
+    headers.append("Set-Cookie", "forwarder=bar; SameSite=None")
+}
+
+/// Check if the client already has the rewritten feed
+fn not_modified(request: &Request, rewritten: &headers::Rewritten) -> Result<bool> {
+    Ok(request
+        .headers()
+        .get("if-none-match")?
+        .is_some_and(|if_none_match| headers::etag_matches(&if_none_match, &rewritten.etag)))
+}
+
+/// Answer `HEAD /` with the headers of `GET /`, without fetching and
+/// rewriting the feed
+///
+/// Only a `HEAD` request is sent upstream. The body-specific headers are
+/// taken from the last rewrite of the same upstream version. If there is
+/// none or the upstream server doesn't support `HEAD`, the feed gets fetched
+/// and rewritten after all.
+async fn feed_head(
+    request: &Request,
+    ctx: &RouteContext<Context>,
+    client: &Client,
+) -> Result<Response> {
+    if let Some(response) = admit(request, ctx, client)? {
+        return Ok(response);
+    }
+    let orig_response = fetch_upstream(request, ctx, Method::Head).await?;
+    let mut response_headers = feed_headers(&orig_response)?;
+    if orig_response.status_code() == 304 {
+        return Ok(Response::empty()?
+            .with_status(304)
+            .with_headers(response_headers));
+    }
+    let rewritten = match upstream_version(&orig_response)? {
+        Some(version) if orig_response.status_code() == 200 => {
+            headers::rewritten(&version, client.name(), request.url()?.as_str())
+        }
+        _ => None,
+    };
+    let Some(rewritten) = rewritten else {
+        let response = rewrite(request, ctx, client).await?;
+        return Ok(Response::empty()?
+            .with_status(response.status_code())
+            .with_headers(response.headers().clone()));
+    };
+    set_feed_headers(&mut response_headers, &rewritten)?;
+    let status = if not_modified(request, &rewritten)? {
+        304
+    } else {
+        200
+    };
+    Ok(Response::empty()?
+        .with_status(status)
+        .with_headers(response_headers))
+}
+
+/// Record a feed fetch of a directory crawler with the version of the
+/// upstream feed it received, if known. Other clients are not recorded
+///
+/// The crawl is recorded after the response went out. Failures only get
+/// logged.
+fn record_crawl(ctx: &RouteContext<Context>, client: &Client, version: Option<directory::Version>) {
+    if client.category() != ClientCategory::DirectoryCrawler {
+        return;
+    }
+    let env: Env = ctx.env.clone().into();
+    let directory = client.name().to_string();
+    ctx.data.wait_until(async move {
+        let now = Date::now().as_millis();
+        if let Err(e) = directory::record(&env, &directory, now, version).await {
+            console_error!("Cannot record crawl: {e}");
+        }
+    });
+}
+
+/// Serve the feed, rewritten for the requesting client
+async fn feed(request: &Request, ctx: &RouteContext<Context>, client: &Client) -> Result<Response> {
+    if let Some(response) = admit(request, ctx, client)? {
+        return Ok(response);
+    }
+    rewrite(request, ctx, client).await
+}
+
+/// Fetch the upstream feed and rewrite it for the requesting client
+async fn rewrite(
+    request: &Request,
+    ctx: &RouteContext<Context>,
+    client: &Client,
+) -> Result<Response> {
+    let mut orig_response = fetch_upstream(request, ctx, Method::Get).await?;
+    let mut response_headers = feed_headers(&orig_response)?;
+
+    if orig_response.status_code() == 304 {
+        // The version the directory received is unknown
+        record_crawl(ctx, client, None);
+        return Ok(Response::empty()?
+            .with_status(304)
+            .with_headers(response_headers));
+    }
+
+    let version = upstream_version(&orig_response)?;
+    let upstream_content = orig_response.text().await?;
+
+    // Remember which version of the upstream feed directories received, so
+    // we know whether they have seen the latest episode
+    let crawled = directory::Version::of(&upstream_content);
+    if client.category() == ClientCategory::DirectoryCrawler {
+        record_crawl(ctx, client, Some(crawled));
+    } else {
+        let env: Env = ctx.env.clone().into();
+        ctx.data.wait_until(async move {
+            if let Err(e) = directory::fetched(&env, crawled).await {
+                console_error!("Cannot remember feed version: {e}");
+            }
+        });
+    }
+
+    let feed_content = rules(ctx)?.apply(client, upstream_content.clone());
+
+    // Rewrite original feed with edge worker URLs, but keep original
+    // mp3 URLs and attach them as encoded string for future forwarding
+    // Also overwrite the link field to the website URL
+    let website = &website(ctx)?;
+    // convert to URL
+    let website = Url::parse(website)?;
+
+    let prefix = route_prefix(ctx);
+    let mut replacer = Replacer::new(website, request.url()?, Some(&prefix))
+        .with_mode(forward_url_mode(ctx)?)
+        .with_prefixes(measurement_prefixes(ctx)?)
+        .with_new_feed_url(new_feed_url(ctx));
+    if let Some(key) = token_key(ctx) {
+        replacer = replacer.with_token_key(key);
+    }
+    if let Some(feed_url) = feed_url(ctx)? {
+        replacer = replacer.with_feed_url(feed_url);
+    }
+    if let Some(template) = item_link_template(ctx) {
+        replacer = replacer.with_item_link_template(&template);
+    }
+    let output = replacer.replace(feed_content.clone());
+
+    // Serve the unmodified upstream feed if the rules or the rewrite broke
+    // something. Losing the download statistics is better than breaking the
+    // feed.
+    let output = match validate::validate(&feed_content, &output, &replacer) {
+        Ok(()) => output,
+        Err(e) => {
+            console_error!("metric={} count=1 error=\"{e}\"", e.metric());
+            upstream_content
+        }
+    };
+
+    // Headers describing the body have to match the rewritten feed
+    let rewritten = headers::Rewritten {
+        etag: headers::etag(&output),
+        length: output.len(),
+    };
+    if let Some(version) = version {
+        headers::remember(
+            &version,
+            client.name(),
+            request.url()?.as_str(),
+            rewritten.clone(),
+        );
+    }
+
+    set_feed_headers(&mut response_headers, &rewritten)?;
+    if not_modified(request, &rewritten)? {
+        return Ok(Response::empty()?
+            .with_status(304)
+            .with_headers(response_headers));
+    }
+
+    Ok(Response::ok(output)?.with_headers(response_headers))
+}
+
+/// Add CORS headers for the origin of the request to the response
+fn with_cors(
+    response: Response,
+    request: &Request,
+    ctx: &RouteContext<Context>,
+) -> Result<Response> {
+    // Clone the headers, the headers of redirects are immutable
+    let mut headers = response.headers().clone();
+    for (key, value) in cors(ctx).headers(request.headers().get("origin")?.as_deref()) {
+        headers.set(&key, &value)?;
+    }
+    Ok(response.with_headers(headers))
+}
+
+/// Answer CORS preflight requests
+fn preflight(request: &Request, ctx: &RouteContext<Context>) -> Result<Response> {
+    let mut headers = Headers::new();
+    let origin = request.headers().get("origin")?;
+    let request_headers = request.headers().get("access-control-request-headers")?;
+    for (key, value) in cors(ctx).preflight_headers(origin.as_deref(), request_headers.as_deref()) {
+        headers.set(&key, &value)?;
+    }
+    Ok(Response::empty()?.with_status(204).with_headers(headers))
+}
+
+/// Answer admin requests without the admin token with `401 Unauthorized`
+fn unauthorized(request: &Request, ctx: &RouteContext<Context>) -> Result<Option<Response>> {
+    let authorization = request.headers().get("authorization")?;
+    if admin::authorized(authorization.as_deref(), admin_token(ctx).as_deref()) {
+        return Ok(None);
+    }
+    Ok(Some(Response::error("Unauthorized", 401)?))
+}
+
+/// Resolve the upstream URL of a media request, then track the request
+///
+/// The redirect doesn't depend on the event: errors of `track`, e.g. a
+/// misconfigured `CLOUDFLARE_FIELDS`, are returned next to the upstream URL,
+/// so they only get logged. Requests without upstream URL are not tracked.
+fn resolve<F>(upstream: Result<Url>, track: F) -> (Result<Url>, Option<Error>)
+where
+    F: FnOnce() -> Result<()>,
+{
+    match upstream {
+        Ok(url) => (Ok(url), track().err()),
+        Err(e) => (Err(e), None),
+    }
+}
+
+/// Redirect media requests to the upstream URL
+///
+/// `HEAD` requests get the same redirect as `GET` requests, so podcast apps
+/// checking the file follow it to the upstream server. Errors of the event
+/// only get logged, the listener always gets the redirect.
+async fn media(request: &Request, ctx: &RouteContext<Context>) -> Result<Response> {
+    let received = Date::now().as_millis();
+    let verdict = rate_limit(request, ctx, Route::Media).await;
+    if let Some(response) = reject(verdict, ctx)? {
+        return Ok(response);
+    }
+    let (upstream, event_error) = resolve(
+        forward::get(request, Some(&route_prefix(ctx)), token_key(ctx).as_ref()),
+        || track(request, ctx, &client(request), received, 302, verdict),
+    );
+    // Analytics must never break playback
+    if let Some(e) = event_error {
+        console_error!("Cannot send media event: {e}");
+    }
+    match upstream {
+        Ok(url) => {
+            console_log!("Forwarding to {url}");
+            Response::redirect(url)
+        }
+        Err(e) => Response::error(e.to_string(), 404),
+    }
+}
 
 /// Handle RSS feed requests by forwarding them to the original URL and logging
 /// the request
@@ -30,7 +478,7 @@ use worker::{console_log, event, Env, Fetch, Method, Request, Response, Result,
 /// * the request could not be forwarded
 /// * the feed URL could not be retrieved from the config
 #[event(fetch)]
-pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Response> {
+pub async fn main(req: Request, env: Env, ctx: Context) -> Result<Response> {
     log_request(&req);
 
     // Get more helpful error messages written to the console in the case of a
@@ -38,86 +486,109 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
     panic::set_panic_hook();
 
     // Use the Router to handle matching endpoints, use ":name" placeholders, or
-    // "*name" catch-alls to match on specific patterns. Alternatively, use
-    // `Router::with_data(D)` to provide arbitrary data that will be accessible
-    // in each route via the `ctx.data()` method.
-    let router = Router::new();
+    // "*name" catch-alls to match on specific patterns. The context of the
+    // fetch event is available in each route as `ctx.data`, for work after
+    // the response went out.
+    let router = Router::with_data(ctx);
+
+    // Forwarding URLs start with this prefix, e.g. `/r/podcast1.mp3?ref=...`
+    let prefix = env_route_prefix(&env);
 
     // Each route will get a `Request` for handling HTTP functionality and a
     // `RouteContext` which you can use to get route parameters and Environment
     // bindings like KV Stores, Durable Objects, Secrets, and Variables.
     router
         .head_async("/", |request, ctx| async move {
-            let upstream = &upstream(&ctx)?;
-            // Fetch original RSS feed.
-            let mut req = Request::new(upstream, Method::Head)?;
-            // Append request headers to the forwarded request
-            let headers = req.headers_mut()?;
-            for (key, value) in request.headers() {
-                headers.append(&key, &value)?;
+            // Answer with the same headers as for `GET /`, but without body
+            let received = Date::now().as_millis();
+            let verdict = rate_limit(&request, &ctx, Route::Feed).await;
+            if let Some(response) = reject(verdict, &ctx)? {
+                return with_cors(response, &request, &ctx);
             }
-
-            let response = Fetch::Request(req).send().await?;
-
-            Ok(response)
+            let client = client(&request);
+            let response = feed_head(&request, &ctx, &client).await?;
+            if let Err(e) = track(
+                &request,
+                &ctx,
+                &client,
+                received,
+                response.status_code(),
+                verdict,
+            ) {
+                console_error!("Cannot send feed event: {e}");
+            }
+            with_cors(response, &request, &ctx)
         })
         // Request for RSS feed
         .get_async("/", |request, ctx| async move {
-            let upstream = &upstream(&ctx)?;
+            let received = Date::now().as_millis();
+            let verdict = rate_limit(&request, &ctx, Route::Feed).await;
+            if let Some(response) = reject(verdict, &ctx)? {
+                return with_cors(response, &request, &ctx);
+            }
             let client = client(&request);
-            console_log!("Received request from {}", client.name());
-
-            // Fetch original RSS feed.
-            let mut orig_response = Fetch::Request(Request::new(upstream, Method::Get)?)
-                .send()
-                .await?;
-            let feed_content = orig_response.text().await?;
-
-            // Rewrite original feed with edge worker URLs, but keep original
-            // mp3 URLs and attach them as encoded string for future forwarding
-            // Also overwrite the link field to the website URL
-            let website = &website(&ctx)?;
-            // convert to URL
-            let website = Url::parse(website)?;
-
-            let output = Replacer::new(website, request.url()?, Some("/r")).replace(feed_content);
-
-            // Pass original request headers to client
-            let mut response = Response::ok(output)?.with_headers(orig_response.headers().clone());
-
-            response
-                .headers_mut()
-                .append("Set-Cookie", "forwarder=bar; SameSite=None")?;
-
-            Ok(response)
+            let response = feed(&request, &ctx, &client).await?;
+            if let Err(e) = track(
+                &request,
+                &ctx,
+                &client,
+                received,
+                response.status_code(),
+                verdict,
+            ) {
+                console_error!("Cannot send feed event: {e}");
+            }
+            with_cors(response, &request, &ctx)
         })
-        .get_async("/r/*forward_url", |request, ctx| async move {
-            match forward::get(&request, Some("/r")) {
-                Ok(url) => {
-                    let openpodcast_client = openpodcast::Client::new(
-                        ctx.var("OPENPODCAST_API_ENDPOINT")?.to_string(),
-                        ctx.var("OPENPODCAST_API_KEY")?.to_string(),
-                    );
-
-                    let event = event::openpodcast(&request, &ctx)?;
-                    let response = openpodcast_client.send(event).await?;
-                    console_log!("OpenPodcast API response: {:#?}", response);
-
-                    println!("Forwarding to {url}");
-                    let response = Response::redirect(url)?;
-
-                    // Clone the response so that it's no longer immutable
-                    // https://community.cloudflare.com/t/how-to-modify-immutable-headers-and-add-nonces-to-response-header/146165
-                    // https://developers.cloudflare.com/workers/examples/alter-headers
-                    let mut new_response = Response::from_body(response.body().clone())?;
-
-                    new_response
-                        .headers_mut()
-                        .append("Set-Cookie", "forwarder=bar; SameSite=None")?;
-                    Ok(response)
-                }
-                Err(e) => Response::error(e.to_string(), 404),
+        .options("/", |request, ctx| preflight(&request, &ctx))
+        // Probe of the media file, e.g. for getting its size
+        .head_async(
+            &format!("{prefix}/*forward_url"),
+            |request, ctx| async move { with_cors(media(&request, &ctx).await?, &request, &ctx) },
+        )
+        .get_async(
+            &format!("{prefix}/*forward_url"),
+            |request, ctx| async move { with_cors(media(&request, &ctx).await?, &request, &ctx) },
+        )
+        .options(&format!("{prefix}/*forward_url"), |request, ctx| {
+            preflight(&request, &ctx)
+        })
+        // Most common user agents missing in the lookup table
+        .get_async("/admin/unknown-user-agents", |request, ctx| async move {
+            if let Some(response) = unauthorized(&request, &ctx)? {
+                return with_cors(response, &request, &ctx);
+            }
+            let report = admin::unknown_user_agents(&ctx.env, &request.url()?).await?;
+            with_cors(Response::from_json(&report)?, &request, &ctx)
+        })
+        .options("/admin/unknown-user-agents", |request, ctx| {
+            preflight(&request, &ctx)
+        })
+        // Last crawls of podcast directories
+        .get_async("/admin/directories", |request, ctx| async move {
+            if let Some(response) = unauthorized(&request, &ctx)? {
+                return with_cors(response, &request, &ctx);
+            }
+            let report = admin::directories(&ctx.env).await?;
+            with_cors(Response::from_json(&report)?, &request, &ctx)
+        })
+        .options("/admin/directories", |request, ctx| {
+            preflight(&request, &ctx)
+        })
+        // Daily subscriber estimates
+        .get_async("/stats/subscribers", |request, ctx| async move {
+            if let Some(response) = unauthorized(&request, &ctx)? {
+                return with_cors(response, &request, &ctx);
             }
+            let report = admin::subscribers(&ctx.env, &request.url()?).await?;
+            with_cors(Response::from_json(&report)?, &request, &ctx)
+        })
+        .options("/stats/subscribers", |request, ctx| {
+            preflight(&request, &ctx)
+        })
+        // JSON Schema of the events, for validating them in the API
+        .get("/schema/event.json", |_, _| {
+            Response::from_json(&event::schema())
         })
         .get("/version", |_, ctx| {
             let version = ctx.var("VERSION")?.to_string();
@@ -126,3 +597,41 @@ pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Respo
         .run(req, env)
         .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cloudflare::Fields;
+    use crate::event::Privacy;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_resolve_independent_of_events() {
+        let url = Url::parse("https://example.com/podcast1.mp3").unwrap();
+
+        let (upstream, error) = resolve(Ok(url.clone()), || Ok(()));
+        assert_eq!(upstream.unwrap(), url);
+        assert!(error.is_none());
+
+        // Misconfigured events still redirect
+        let (upstream, error) = resolve(Ok(url.clone()), || {
+            Fields::default().with_allow("lol").map(|_| ())
+        });
+        assert_eq!(upstream.unwrap(), url);
+        assert!(error.is_some());
+        let (upstream, error) = resolve(Ok(url.clone()), || {
+            Privacy::default().with_signals("dnt, lol").map(|_| ())
+        });
+        assert_eq!(upstream.unwrap(), url);
+        assert!(error.is_some());
+
+        // Requests without upstream URL are not tracked
+        let (upstream, error) = resolve(
+            Err(Error::RustError("Could not find ref parameter".to_string())),
+            || unreachable!("no event without upstream URL"),
+        );
+        assert!(upstream.is_err());
+        assert!(error.is_none());
+    }
+}
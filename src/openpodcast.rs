@@ -2,7 +2,7 @@
 //!
 //! # Example
 //!
-//! ```rust
+//! ```rust,ignore
 //! use openpodcast::Client;
 //!
 //! let client = Client::new("token");
@@ -10,7 +10,7 @@
 //!    "ip": "127.0.0.1",
 //!    "user_agent": "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/91.0.4472.114 Safari/537.36",
 //! });
-//! let response = client.send(json).await?;
+//! let response = client.send(json, "7a0b1c2d3e4f5a6b-FRA").await?;
 //! assert_eq!(response.status(), 200);
 //! ```
 //!
@@ -33,8 +33,13 @@ impl Client {
         }
     }
 
-    /// Send a request to the API
-    pub async fn send(&self, data: serde_json::Value) -> Result<reqwest::Response> {
+    /// Send a request to the API. The API uses the `idempotency_key` to
+    /// deduplicate retried deliveries of the same data.
+    pub async fn send(
+        &self,
+        data: serde_json::Value,
+        idempotency_key: &str,
+    ) -> Result<reqwest::Response> {
         // let data = JsValue::from_serde(&data)?;
         // console_log!("data: {:?}", data);
         // convert [Object object]
@@ -62,6 +67,11 @@ impl Client {
                 .map_err(|e| worker::Error::from(e.to_string()))?,
         );
         headers.insert("Content-Type", HeaderValue::from_static("application/json"));
+        headers.insert(
+            "Idempotency-Key",
+            HeaderValue::from_str(idempotency_key)
+                .map_err(|e| worker::Error::from(e.to_string()))?,
+        );
 
         let response = client
             .post(&self.endpoint)
@@ -0,0 +1,166 @@
+//! Third-party measurement prefixes
+//!
+//! Many feeds already wrap their enclosures in prefixes of other analytics
+//! services, e.g.
+//! `https://dts.podtrac.com/redirect.mp3/chtbl.com/track/1234/example.com/podcast1.mp3`.
+//! The parser in this module unwraps such a chain of known prefixes to get
+//! the media URL at the end of it.
+
+use regex::Regex;
+use std::str::FromStr;
+use std::sync::LazyLock;
+use url::{Position, Url};
+use worker::{Error, Result};
+
+/// Known measurement prefixes as (name, pattern) pairs.
+/// The pattern matches the prefix without the scheme, including the trailing
+/// slash.
+static PREFIXES: LazyLock<Vec<(&str, Regex)>> = LazyLock::new(|| {
+    [
+        ("podtrac", r"^dts\.podtrac\.com/redirect\.[a-z0-9]+/"),
+        ("podtrac", r"^(www\.)?podtrac\.com/pts/redirect\.[a-z0-9]+/"),
+        ("chartable", r"^chtbl\.com/track/[^/]+/"),
+        ("chartable", r"^chrt\.fm/track/[^/]+/"),
+        ("podsights", r"^pdst\.fm/e/"),
+        ("op3", r"^op3\.dev/e(,[^/]*)?/"),
+        ("podscribe", r"^(pscrb\.fm|verifi\.podscribe\.com)/rss/p/"),
+        ("spotify", r"^prfx\.byspotify\.com/e/"),
+        ("magellan", r"^mgln\.ai/e/[^/]+/"),
+        ("artsai", r"^arttrk\.com/p/[^/]+/"),
+    ]
+    .into_iter()
+    .map(|(name, pattern)| (name, Regex::new(pattern).unwrap()))
+    .collect()
+});
+
+/// What to do with measurement prefixes of upstream URLs when rewriting the
+/// feed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Policy {
+    /// Forward to the upstream URL including all prefixes, so the other
+    /// services keep counting downloads
+    #[default]
+    Keep,
+    /// Forward to the media URL directly
+    Strip,
+}
+
+impl FromStr for Policy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "keep" => Ok(Self::Keep),
+            "strip" => Ok(Self::Strip),
+            _ => Err(Error::RustError(format!(
+                "Unknown measurement prefix policy: {s}"
+            ))),
+        }
+    }
+}
+
+/// Upstream URL split into its measurement prefixes and the media URL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chain {
+    /// Names of the measurement services in the order of the chain
+    pub prefixes: Vec<&'static str>,
+    /// URL of the media file at the end of the chain
+    pub media: Url,
+}
+
+/// Unwrap all known measurement prefixes of the given URL
+///
+/// Prefixes either contain the next URL without scheme (then the scheme of
+/// the outer URL is used) or with an explicit `http://` or `https://`.
+/// If the remaining URL is invalid, the chain ends before the last prefix.
+#[must_use]
+pub fn parse(url: &Url) -> Chain {
+    let mut chain = Chain {
+        prefixes: Vec::new(),
+        media: url.clone(),
+    };
+    let mut scheme = url.scheme().to_string();
+    let mut rest = &url[Position::BeforeHost..];
+    while let Some((name, prefix)) = PREFIXES
+        .iter()
+        .find_map(|(name, regex)| regex.find(rest).map(|prefix| (*name, prefix.end())))
+    {
+        rest = &rest[prefix..];
+        for explicit in ["https", "http"] {
+            if let Some(stripped) = rest.strip_prefix(&format!("{explicit}://")) {
+                scheme = explicit.to_string();
+                rest = stripped;
+                break;
+            }
+        }
+        let Ok(media) = Url::parse(&format!("{scheme}://{rest}")) else {
+            break;
+        };
+        chain.prefixes.push(name);
+        chain.media = media;
+    }
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    fn parse_str(url: &str) -> (Vec<&'static str>, String) {
+        let chain = parse(&Url::parse(url).unwrap());
+        (chain.prefixes, chain.media.to_string())
+    }
+
+    #[test]
+    fn test_no_prefix() {
+        assert_eq!(
+            parse_str("https://example.com/podcast1.mp3?a=1"),
+            (vec![], "https://example.com/podcast1.mp3?a=1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_single_prefixes() {
+        for url in [
+            "https://dts.podtrac.com/redirect.mp3/example.com/podcast1.mp3",
+            "https://www.podtrac.com/pts/redirect.aac/example.com/podcast1.mp3",
+            "https://chtbl.com/track/ABC123/example.com/podcast1.mp3",
+            "https://pdst.fm/e/example.com/podcast1.mp3",
+            "https://op3.dev/e/example.com/podcast1.mp3",
+            "https://op3.dev/e,pg=6b5d5bc4/https://example.com/podcast1.mp3",
+            "https://pscrb.fm/rss/p/example.com/podcast1.mp3",
+        ] {
+            let (prefixes, media) = parse_str(url);
+            assert_eq!(prefixes.len(), 1, "{url}");
+            assert_eq!(media, "https://example.com/podcast1.mp3", "{url}");
+        }
+    }
+
+    #[test]
+    fn test_prefix_chain() {
+        assert_eq!(
+            parse_str("http://dts.podtrac.com/redirect.mp3/chtbl.com/track/1234/pdst.fm/e/https://stream.example.com/ep/1.mp3?x=1"),
+            (
+                vec!["podtrac", "chartable", "podsights"],
+                "https://stream.example.com/ep/1.mp3?x=1".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_prefix_without_media_url() {
+        assert_eq!(
+            parse_str("https://dts.podtrac.com/redirect.mp3/"),
+            (vec![], "https://dts.podtrac.com/redirect.mp3/".to_string())
+        );
+    }
+
+    #[test]
+    fn test_policy_from_str() {
+        assert_eq!("keep".parse::<Policy>().unwrap(), Policy::Keep);
+        assert_eq!("strip".parse::<Policy>().unwrap(), Policy::Strip);
+        assert!("lol".parse::<Policy>().is_err());
+    }
+}
@@ -0,0 +1,418 @@
+//! Token-bucket rate limiting for the feed and media routes
+//!
+//! Every combination of route, client IP and user agent gets a bucket of
+//! tokens which refills continuously. A request takes one token, requests
+//! finding an empty bucket are limited. What happens to limited requests is
+//! up to the configured `Action`.
+//!
+//! Token counts are stored in thousandths of a token, so refilling works with
+//! integer math on millisecond timestamps.
+//!
+//! The buckets live in the `RateLimits` Durable Object, one object per key, so
+//! the limits hold across isolates and Cloudflare locations. A full bucket is
+//! the same as no bucket, so objects clear their storage with an alarm once
+//! their bucket is full again.
+
+use crate::durable;
+use serde::{Deserialize, Serialize};
+#[cfg(test)]
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use worker::wasm_bindgen::JsCast;
+use worker::worker_sys::durable_object::ObjectState;
+use worker::{
+    async_trait, durable_object, js_sys, wasm_bindgen, wasm_bindgen_futures, worker_sys, Env,
+    Error, Request, Response, Result, State,
+};
+
+/// Binding of the `RateLimits` Durable Object in `wrangler.toml`
+const BINDING: &str = "RATE_LIMITS";
+
+/// Thousandths of a token a single request costs
+const COST: u64 = 1000;
+
+/// Routes with separate limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    /// `GET /` and `HEAD /`
+    Feed,
+    /// Forwarding of media files
+    Media,
+}
+
+impl Route {
+    /// Short name of the route, used in bucket keys
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Feed => "feed",
+            Self::Media => "media",
+        }
+    }
+}
+
+/// What to do with requests exceeding the limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Action {
+    /// Serve the request, but mark its event as abusive
+    #[default]
+    Mark,
+    /// Serve the request, but don't send an event
+    Drop,
+    /// Answer with `429 Too Many Requests`
+    Reject,
+}
+
+impl FromStr for Action {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mark" => Ok(Self::Mark),
+            "drop" => Ok(Self::Drop),
+            "reject" => Ok(Self::Reject),
+            _ => Err(Error::RustError(format!("Unknown rate limit action: {s}"))),
+        }
+    }
+}
+
+/// Number of requests allowed per period, e.g. `60/minute`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Limit {
+    /// Requests per period, also the size of the bucket
+    requests: u64,
+    /// Length of the period in milliseconds
+    period: u64,
+}
+
+impl FromStr for Limit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::RustError(format!("Invalid rate limit: {s}"));
+        let (requests, period) = s.split_once('/').ok_or_else(invalid)?;
+        let requests = requests.trim().parse().map_err(|_| invalid())?;
+        let period = match period.trim() {
+            "second" => 1000,
+            "minute" => 60 * 1000,
+            "hour" => 60 * 60 * 1000,
+            "day" => 24 * 60 * 60 * 1000,
+            _ => return Err(invalid()),
+        };
+        if requests == 0 {
+            return Err(invalid());
+        }
+        Ok(Self { requests, period })
+    }
+}
+
+/// Fill level of a bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bucket {
+    /// Thousandths of tokens left
+    tokens: u64,
+    /// Time of the last update in milliseconds
+    updated: u64,
+}
+
+impl Bucket {
+    /// Time (in milliseconds) at which the bucket is full again
+    #[must_use]
+    pub const fn full_at(&self, limit: &Limit) -> u64 {
+        let capacity = limit.requests * COST;
+        let missing = capacity.saturating_sub(self.tokens);
+        self.updated + (missing * limit.period).div_ceil(capacity)
+    }
+}
+
+/// Storage of buckets
+#[async_trait::async_trait(?Send)]
+pub trait Storage {
+    /// Load the bucket with the given key
+    async fn load(&self, key: &str) -> Result<Option<Bucket>>;
+    /// Store the bucket with the given key
+    async fn store(&mut self, key: &str, bucket: Bucket) -> Result<()>;
+}
+
+/// Buckets kept in memory, for tests
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MemoryStorage(HashMap<String, Bucket>);
+
+#[cfg(test)]
+#[async_trait::async_trait(?Send)]
+impl Storage for MemoryStorage {
+    async fn load(&self, key: &str) -> Result<Option<Bucket>> {
+        Ok(self.0.get(key).copied())
+    }
+
+    async fn store(&mut self, key: &str, bucket: Bucket) -> Result<()> {
+        self.0.insert(key.to_string(), bucket);
+        Ok(())
+    }
+}
+
+/// Buckets kept in the storage of a Durable Object
+pub struct DurableStorage(worker::Storage);
+
+#[async_trait::async_trait(?Send)]
+impl Storage for DurableStorage {
+    async fn load(&self, key: &str) -> Result<Option<Bucket>> {
+        durable::get(&self.0, key).await
+    }
+
+    async fn store(&mut self, key: &str, bucket: Bucket) -> Result<()> {
+        self.0.put(key, bucket).await
+    }
+}
+
+/// Result of a rate limit check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Verdict {
+    /// The request is within the limit
+    Allow,
+    /// The request exceeds the limit. Retrying makes sense after the given
+    /// number of seconds.
+    Limited { retry_after: u64 },
+}
+
+/// Key of the bucket for a request
+#[must_use]
+pub fn key(route: Route, ip: Option<IpAddr>, user_agent: Option<&str>) -> String {
+    format!(
+        "{}|{}|{}",
+        route.name(),
+        ip.map(|ip| ip.to_string()).unwrap_or_default(),
+        user_agent.unwrap_or_default()
+    )
+}
+
+/// Token-bucket rate limiter
+#[derive(Debug, Default)]
+pub struct RateLimiter<S> {
+    storage: S,
+}
+
+impl<S: Storage> RateLimiter<S> {
+    /// Create a rate limiter with the given storage
+    pub const fn new(storage: S) -> Self {
+        Self { storage }
+    }
+
+    /// Take a token from the bucket with the given key at time `now` (in
+    /// milliseconds)
+    ///
+    /// # Errors
+    ///
+    /// * the storage fails
+    pub async fn check(&mut self, key: &str, limit: &Limit, now: u64) -> Result<Verdict> {
+        let capacity = limit.requests * COST;
+        let bucket = self.storage.load(key).await?.map_or(
+            Bucket {
+                tokens: capacity,
+                updated: now,
+            },
+            |bucket| {
+                let elapsed = now.saturating_sub(bucket.updated);
+                let refill = elapsed.saturating_mul(capacity) / limit.period;
+                Bucket {
+                    tokens: bucket.tokens.saturating_add(refill).min(capacity),
+                    updated: now,
+                }
+            },
+        );
+        if bucket.tokens >= COST {
+            self.storage
+                .store(
+                    key,
+                    Bucket {
+                        tokens: bucket.tokens - COST,
+                        ..bucket
+                    },
+                )
+                .await?;
+            return Ok(Verdict::Allow);
+        }
+        self.storage.store(key, bucket).await?;
+        // Time until the bucket has a full token again, rounded up to seconds
+        let missing = (COST - bucket.tokens) * limit.period / capacity;
+        Ok(Verdict::Limited {
+            retry_after: missing.div_ceil(1000).max(1),
+        })
+    }
+}
+
+/// Rate limit check sent to the `RateLimits` Durable Object
+#[derive(Debug, Serialize, Deserialize)]
+struct Check {
+    key: String,
+    limit: Limit,
+    now: u64,
+}
+
+/// Durable Object holding the bucket of a key
+#[durable_object]
+pub struct RateLimits {
+    state: State,
+    /// State of the JavaScript runtime, for setting alarms
+    raw: ObjectState,
+}
+
+#[durable_object]
+impl DurableObject for RateLimits {
+    fn new(state: State, _env: Env) -> Self {
+        let raw = state._inner();
+        // `ObjectState` only clones as its JavaScript base object
+        let state = State::from(raw.clone().unchecked_into::<ObjectState>());
+        Self { state, raw }
+    }
+
+    // `&mut self` is required by `DurableObject`
+    #[allow(clippy::needless_pass_by_ref_mut)]
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let Check { key, limit, now } = req.json().await?;
+        let mut limiter = RateLimiter::new(DurableStorage(self.state.storage()));
+        let verdict = limiter.check(&key, &limit, now).await?;
+        // Every check moves the alarm, so it only goes off if the bucket
+        // didn't change until it was full again
+        if let Some(bucket) = limiter.storage.load(&key).await? {
+            durable::set_alarm(&self.raw, bucket.full_at(&limit)).await?;
+        }
+        Response::from_json(&verdict)
+    }
+}
+
+#[wasm_bindgen::prelude::wasm_bindgen]
+impl RateLimits {
+    /// Clear the storage once the bucket is full again, see `fetch`
+    #[wasm_bindgen(js_name = alarm)]
+    pub fn alarm(&mut self) -> js_sys::Promise {
+        let mut storage = self.state.storage();
+        wasm_bindgen_futures::future_to_promise(async move {
+            storage.delete_all().await?;
+            Ok(wasm_bindgen::JsValue::UNDEFINED)
+        })
+    }
+}
+
+/// Take a token from the bucket with the given key at time `now` (in
+/// milliseconds)
+///
+/// # Errors
+///
+/// * the `RateLimits` Durable Object can't be reached
+pub async fn check(env: &Env, key: &str, limit: Limit, now: u64) -> Result<Verdict> {
+    let check = Check {
+        key: key.to_string(),
+        limit,
+        now,
+    };
+    durable::call(env, BINDING, key, "/check", &check).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures_executor::block_on;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_limit_from_str() {
+        assert_eq!(
+            "60/minute".parse::<Limit>().unwrap(),
+            Limit {
+                requests: 60,
+                period: 60_000
+            }
+        );
+        assert_eq!(
+            " 2 / second ".parse::<Limit>().unwrap(),
+            Limit {
+                requests: 2,
+                period: 1000
+            }
+        );
+        assert!("0/minute".parse::<Limit>().is_err());
+        assert!("60".parse::<Limit>().is_err());
+        assert!("60/fortnight".parse::<Limit>().is_err());
+    }
+
+    #[test]
+    fn test_action_from_str() {
+        assert_eq!("mark".parse::<Action>().unwrap(), Action::Mark);
+        assert_eq!("drop".parse::<Action>().unwrap(), Action::Drop);
+        assert_eq!("reject".parse::<Action>().unwrap(), Action::Reject);
+        assert!("lol".parse::<Action>().is_err());
+    }
+
+    #[test]
+    fn test_key() {
+        assert_eq!(
+            key(Route::Media, "192.0.2.1".parse().ok(), Some("Overcast")),
+            "media|192.0.2.1|Overcast"
+        );
+        assert_eq!(key(Route::Feed, None, None), "feed||");
+    }
+
+    #[test]
+    fn test_check() {
+        let mut limiter = RateLimiter::new(MemoryStorage::default());
+        let limit = "2/minute".parse().unwrap();
+        assert_eq!(
+            block_on(limiter.check("a", &limit, 0)).unwrap(),
+            Verdict::Allow
+        );
+        assert_eq!(
+            block_on(limiter.check("a", &limit, 0)).unwrap(),
+            Verdict::Allow
+        );
+        assert_eq!(
+            block_on(limiter.check("a", &limit, 0)).unwrap(),
+            Verdict::Limited { retry_after: 30 }
+        );
+        // Other keys have their own bucket
+        assert_eq!(
+            block_on(limiter.check("b", &limit, 0)).unwrap(),
+            Verdict::Allow
+        );
+        // Half a token after 15 seconds
+        assert_eq!(
+            block_on(limiter.check("a", &limit, 15_000)).unwrap(),
+            Verdict::Limited { retry_after: 15 }
+        );
+        assert_eq!(
+            block_on(limiter.check("a", &limit, 30_000)).unwrap(),
+            Verdict::Allow
+        );
+        // The bucket never holds more than the limit
+        assert_eq!(
+            block_on(limiter.check("a", &limit, 3_600_000)).unwrap(),
+            Verdict::Allow
+        );
+        assert_eq!(
+            block_on(limiter.check("a", &limit, 3_600_000)).unwrap(),
+            Verdict::Allow
+        );
+        assert!(matches!(
+            block_on(limiter.check("a", &limit, 3_600_000)).unwrap(),
+            Verdict::Limited { .. }
+        ));
+    }
+
+    #[test]
+    fn test_full_at() {
+        let limit = "2/minute".parse().unwrap();
+        let bucket = |tokens| Bucket {
+            tokens,
+            updated: 1000,
+        };
+        assert_eq!(bucket(2 * COST).full_at(&limit), 1000);
+        assert_eq!(bucket(COST).full_at(&limit), 31_000);
+        assert_eq!(bucket(0).full_at(&limit), 61_000);
+        // Rounded up, so the bucket is really full
+        let limit = "7/minute".parse().unwrap();
+        assert_eq!(bucket(1).full_at(&limit), 60_992);
+    }
+}
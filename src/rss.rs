@@ -1,9 +1,78 @@
+use crate::codec::{self, Mode, TokenKey};
+use crate::prefix::{self, Policy};
 use regex::Regex;
 use std::collections::HashMap;
+use std::sync::LazyLock;
 use url::Url;
 
-const LINK_REGEX: &str = r#"<link>(?P<url>.*?)</link>"#;
+const LINK_REGEX: &str = r"<link>(?P<url>.*?)</link>";
 const ENCLOSURE_URL_REGEX: &str = r#"<enclosure.*url=("|')(?P<url>.*?)("|')"#;
+const ATOM_LINK_REGEX: &str = r"<atom:link\s[^>]*>";
+const SELF_REL_REGEX: &str = r#"\srel=("|')self("|')"#;
+const HREF_REGEX: &str = r#"\shref=("|')(?P<url>.*?)("|')"#;
+const NEW_FEED_URL_REGEX: &str = r"(?s)<itunes:new-feed-url>.*?</itunes:new-feed-url>";
+/// Regex for finding the start of items
+static ITEM_START_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<item[\s>]").unwrap());
+const ITEM_REGEX: &str = r"(?s)<item[\s>].*?</item>";
+const PLACEHOLDER_REGEX: &str = r"\{(?P<tag>[A-Za-z0-9_:.-]+)\}";
+const CDATA_REGEX: &str = r"(?s)^\s*<!\[CDATA\[(?P<value>.*?)\]\]>\s*$";
+
+/// Template for item-level `<link>` elements, e.g. `{website}/episodes/{guid}`
+///
+/// `{website}` is replaced with the website URL, every other placeholder with
+/// the (URL-encoded) content of the item element of the same name, e.g.
+/// `{itunes:episode}`.
+struct LinkTemplate {
+    template: String,
+    /// Placeholders and the regexes to find the corresponding item elements
+    placeholders: Vec<(String, Regex)>,
+    /// Regex for unwrapping `<![CDATA[...]]>` element content
+    cdata_regex: Regex,
+}
+
+impl LinkTemplate {
+    fn new(template: &str) -> Self {
+        let placeholders = Regex::new(PLACEHOLDER_REGEX)
+            .unwrap()
+            .captures_iter(template)
+            .filter(|caps| &caps["tag"] != "website")
+            .map(|caps| {
+                let tag = regex::escape(&caps["tag"]);
+                (
+                    caps[0].to_string(),
+                    Regex::new(&format!(r"(?s)<{tag}(\s[^>]*)?>(?P<value>.*?)</{tag}>")).unwrap(),
+                )
+            })
+            .collect();
+        Self {
+            template: template.to_string(),
+            placeholders,
+            cdata_regex: Regex::new(CDATA_REGEX).unwrap(),
+        }
+    }
+
+    /// Render the link for the given item. Returns `None` if the item lacks
+    /// an element used in the template.
+    #[allow(clippy::literal_string_with_formatting_args)]
+    fn render(&self, website: &Url, item: &str) -> Option<String> {
+        let mut link = self
+            .template
+            .replace("{website}", website.as_str().trim_end_matches('/'));
+        for (placeholder, regex) in &self.placeholders {
+            let value = regex.captures(item)?.name("value")?.as_str();
+            let value = self
+                .cdata_regex
+                .captures(value)
+                .and_then(|caps| caps.name("value"))
+                .map_or_else(
+                    || html_escape::decode_html_entities(value).to_string(),
+                    |value| value.as_str().to_string(),
+                );
+            link = link.replace(placeholder, &urlencoding::encode(value.trim()));
+        }
+        Some(html_escape::encode_text(&link).to_string())
+    }
+}
 
 /// Set an optional path prefix if specified
 ///
@@ -21,6 +90,52 @@ fn set_prefix<'a>(url: &'a mut Url, prefix: &str) -> &'a mut Url {
     url
 }
 
+/// Insert XML into the channel before the first item. Feeds without items get
+/// it appended to the end of the channel.
+pub fn inject(feed: &str, xml: &str) -> String {
+    let position = ITEM_START_REGEX
+        .find(feed)
+        .map(|m| m.start())
+        .or_else(|| feed.find("</channel>"));
+    position.map_or_else(
+        || feed.to_string(),
+        |position| format!("{}{xml}\n{}", &feed[..position], &feed[position..]),
+    )
+}
+
+/// Items of a feed, for `latest_episode`
+static ITEM: LazyLock<Regex> = LazyLock::new(|| Regex::new(ITEM_REGEX).unwrap());
+
+/// Values wrapped in CDATA sections, for `latest_episode`
+static CDATA: LazyLock<Regex> = LazyLock::new(|| Regex::new(CDATA_REGEX).unwrap());
+
+/// Elements identifying an episode, in order of preference
+static EPISODE_IDS: LazyLock<[Regex; 2]> = LazyLock::new(|| {
+    ["guid", "title"]
+        .map(|tag| Regex::new(&format!(r"(?s)<{tag}(\s[^>]*)?>(?P<value>.*?)</{tag}>")).unwrap())
+});
+
+/// Identify the latest episode of a feed by the `guid` of its first item, or
+/// its `title` if it has no `guid`
+#[must_use]
+pub fn latest_episode(feed: &str) -> Option<String> {
+    let item = ITEM.find(feed)?.as_str();
+    EPISODE_IDS.iter().find_map(|regex| {
+        let value = regex.captures(item)?.name("value")?.as_str();
+        let value = CDATA
+            .captures(value)
+            .and_then(|caps| caps.name("value"))
+            .map_or(value, |value| value.as_str());
+        Some(value.trim().to_string())
+    })
+}
+
+/// Check if the given enclosure URL is an mp3 file which gets forwarded
+#[must_use]
+pub fn is_rewritable(url: &Url) -> bool {
+    url.path().ends_with("mp3") && (url.scheme() == "http" || url.scheme() == "https")
+}
+
 /// Replaces the domain of mp3 links inside RSS enclosure elements
 /// as well as the link elements
 pub struct Replacer {
@@ -30,54 +145,231 @@ pub struct Replacer {
     forward_url: Url,
     /// Optional path prefix for replaced URLs
     path_prefix: Option<String>,
+    /// Format of the upstream URL inside replaced URLs
+    mode: Mode,
+    /// Key for sealing tokens in the `token` format
+    token_key: Option<TokenKey>,
+    /// Whether to keep measurement prefixes of upstream URLs
+    prefixes: Policy,
+    /// Public URL of the forwarded feed, used for the `atom:link` self link
+    feed_url: Url,
+    /// Optional template for item-level `<link>` elements
+    item_link_template: Option<LinkTemplate>,
+    /// Whether to tell podcast apps to move to `feed_url` for good with an
+    /// `<itunes:new-feed-url>` element
+    new_feed_url: bool,
     /// Regex for finding `<link>` elements
     link_regex: Regex,
+    /// Regex for finding `<item>` elements
+    item_regex: Regex,
     /// Regex for finding mp3 links
     enclosure_regex: Regex,
+    /// Regex for finding `<atom:link>` elements
+    atom_link_regex: Regex,
+    /// Regex for finding the `rel="self"` attribute
+    self_rel_regex: Regex,
+    /// Regex for finding the `href` attribute
+    href_regex: Regex,
+    /// Regex for finding `<itunes:new-feed-url>` elements
+    new_feed_url_regex: Regex,
 }
 
 impl Replacer {
     #[must_use]
     /// Construct a replacer with the given forwarder domain
     pub fn new(link_url: Url, forward_url: Url, path_prefix: Option<&str>) -> Self {
+        let mut feed_url = forward_url.clone();
+        feed_url.set_query(None);
+        feed_url.set_fragment(None);
         Self {
             link_url,
             forward_url,
             path_prefix: path_prefix.map(Into::into),
+            mode: Mode::default(),
+            token_key: None,
+            prefixes: Policy::default(),
+            feed_url,
+            item_link_template: None,
+            new_feed_url: false,
             link_regex: Regex::new(LINK_REGEX).unwrap(),
+            item_regex: Regex::new(ITEM_REGEX).unwrap(),
             enclosure_regex: Regex::new(ENCLOSURE_URL_REGEX).unwrap(),
+            atom_link_regex: Regex::new(ATOM_LINK_REGEX).unwrap(),
+            self_rel_regex: Regex::new(SELF_REL_REGEX).unwrap(),
+            href_regex: Regex::new(HREF_REGEX).unwrap(),
+            new_feed_url_regex: Regex::new(NEW_FEED_URL_REGEX).unwrap(),
+        }
+    }
+
+    /// Override the public URL of the forwarded feed.
+    /// Defaults to the forwarder URL without query parameters.
+    #[must_use]
+    pub fn with_feed_url(mut self, feed_url: Url) -> Self {
+        self.feed_url = feed_url;
+        self
+    }
+
+    /// Use the given template for item-level `<link>` elements, e.g.
+    /// `{website}/episodes/{guid}`. Without a template, the upstream item
+    /// links are kept.
+    #[must_use]
+    pub fn with_item_link_template(mut self, template: &str) -> Self {
+        self.item_link_template = Some(LinkTemplate::new(template));
+        self
+    }
+
+    /// Inject or override `<itunes:new-feed-url>` with the public feed URL to
+    /// migrate subscribers to the forwarder
+    #[must_use]
+    pub const fn with_new_feed_url(mut self, new_feed_url: bool) -> Self {
+        self.new_feed_url = new_feed_url;
+        self
+    }
+
+    /// Set the format of the upstream URL inside replaced URLs
+    #[must_use]
+    pub const fn with_mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Seal tokens with the given key. Without a key, the `token` format
+    /// falls back to the `ref` format
+    #[must_use]
+    pub const fn with_token_key(mut self, key: TokenKey) -> Self {
+        self.token_key = Some(key);
+        self
+    }
+
+    /// Keep or strip third-party measurement prefixes of upstream URLs
+    #[must_use]
+    pub const fn with_prefixes(mut self, prefixes: Policy) -> Self {
+        self.prefixes = prefixes;
+        self
+    }
+
+    /// Upstream URL which replaced URLs forward to, i.e. the original URL
+    /// without measurement prefixes if these get stripped
+    #[must_use]
+    pub fn upstream(&self, orig: &Url) -> Url {
+        match self.prefixes {
+            Policy::Keep => orig.clone(),
+            Policy::Strip => prefix::parse(orig).media,
         }
     }
 
+    /// Path prefix of replaced URLs
+    pub(crate) fn path_prefix(&self) -> Option<&str> {
+        self.path_prefix.as_deref()
+    }
+
+    /// Key for sealing tokens, if set
+    pub(crate) const fn token_key(&self) -> Option<&TokenKey> {
+        self.token_key.as_ref()
+    }
+
     /// Extract all links from an arbitrary string input
-    fn extract(&self, input: &str) -> Vec<String> {
+    pub(crate) fn extract(&self, input: &str) -> Vec<String> {
         self.enclosure_regex
             .captures_iter(input)
             .filter_map(|c| c.name("url").map(|m| m.as_str().to_owned()))
             .collect()
     }
 
+    /// Check if the given URL already is one of our forwarding URLs, e.g.
+    /// because the upstream feed went through the forwarder before
+    #[must_use]
+    pub fn is_forwarded(&self, url: &Url) -> bool {
+        codec::is_forwarding_url(url, &self.forward_url, self.path_prefix())
+    }
+
     /// Extract all valid MP3 links from an arbitrary string input
+    ///
+    /// Links which already point at the forwarder are skipped, so replacing
+    /// a feed twice doesn't wrap them again.
     fn extract_mp3s(&self, input: &str) -> Vec<Url> {
         let links = self.extract(input);
         links
             .into_iter()
             .map(|link| Url::parse(&link))
             .filter_map(Result::ok)
-            .filter(|url| url.path().ends_with("mp3"))
-            .filter(|url| url.scheme() == "http" || url.scheme() == "https")
+            .filter(is_rewritable)
+            .filter(|url| !self.is_forwarded(url))
             .collect()
     }
 
     /// Replace `<link>` elements with the new domain
+    ///
+    /// Channel-level links point to the website. Item-level links are
+    /// rendered from the item link template if there is one and kept
+    /// otherwise.
     fn replace_links(&self, input: &str) -> String {
+        let replace_channel_links = |input: &str| {
+            self.link_regex
+                .replace_all(input, |_caps: &regex::Captures| {
+                    format!("<link>{}</link>", self.link_url)
+                })
+                .to_string()
+        };
+
+        let mut output = String::with_capacity(input.len());
+        let mut last = 0;
+        for item in self.item_regex.find_iter(input) {
+            output.push_str(&replace_channel_links(&input[last..item.start()]));
+            output.push_str(&self.replace_item_links(item.as_str()));
+            last = item.end();
+        }
+        output.push_str(&replace_channel_links(&input[last..]));
+        output
+    }
+
+    /// Replace the `<link>` element of an item with the rendered item link
+    /// template
+    fn replace_item_links(&self, item: &str) -> String {
+        let Some(template) = &self.item_link_template else {
+            return item.to_string();
+        };
+        let Some(link) = template.render(&self.link_url, item) else {
+            return item.to_string();
+        };
         self.link_regex
-            .replace_all(input, |_caps: &regex::Captures| {
-                format!("<link>{}</link>", self.link_url)
+            .replace_all(item, |_caps: &regex::Captures| {
+                format!("<link>{link}</link>")
+            })
+            .to_string()
+    }
+
+    /// Point the `<atom:link rel="self">` element to the forwarded feed
+    fn replace_self_link(&self, input: &str) -> String {
+        let feed_url = html_escape::encode_double_quoted_attribute(self.feed_url.as_str());
+        self.atom_link_regex
+            .replace_all(input, |caps: &regex::Captures| {
+                let link = &caps[0];
+                if !self.self_rel_regex.is_match(link) {
+                    return link.to_string();
+                }
+                self.href_regex
+                    .replace(link, format!(r#" href="{feed_url}""#).as_str())
+                    .to_string()
             })
             .to_string()
     }
 
+    /// Inject or override the `<itunes:new-feed-url>` element
+    fn replace_new_feed_url(&self, input: &str) -> String {
+        let element = format!(
+            "<itunes:new-feed-url>{}</itunes:new-feed-url>",
+            html_escape::encode_text(self.feed_url.as_str())
+        );
+        if self.new_feed_url_regex.is_match(input) {
+            self.new_feed_url_regex
+                .replace_all(input, element.as_str())
+                .to_string()
+        } else {
+            inject(input, &element)
+        }
+    }
+
     /// Replaces the domain of all mp3 links which were found
     /// Uses an ad-hoc lookup table for replacing old with new links
     ///
@@ -89,9 +381,10 @@ impl Replacer {
         let lookup_table: HashMap<String, String> = mp3s
             .into_iter()
             .map(|orig| {
+                let upstream = self.upstream(&orig);
                 let mut replaced = self.forward_url.clone();
 
-                replaced.set_path(orig.path());
+                replaced.set_path(upstream.path());
 
                 // Set optional prefix if specified
                 // example.com/podcast.mp3 -> example.com/r/podcast.mp3
@@ -99,15 +392,17 @@ impl Replacer {
                     set_prefix(&mut replaced, prefix);
                 }
 
-                replaced.query_pairs_mut().append_pair("ref", orig.as_str());
-
-                (
-                    orig.as_str().to_string(),
-                    // Escape `&` character as HTML entities to make feed readable in browser
-                    // See https://stackoverflow.com/a/17918240/270334
-                    // See https://docs.rs/html-escape/latest/html_escape/
-                    html_escape::encode_text(replaced.as_str()).to_string(),
-                )
+                let replaced = match self.mode {
+                    Mode::Ref => codec::encode(replaced, &upstream),
+                    Mode::Token => match &self.token_key {
+                        Some(key) => codec::encode_token(replaced, &upstream, key),
+                        None => codec::encode(replaced, &upstream),
+                    },
+                    Mode::Path => {
+                        codec::encode_path(self.forward_url.clone(), &upstream, self.path_prefix())
+                    }
+                };
+                (orig.as_str().to_string(), replaced)
             })
             .collect();
 
@@ -115,19 +410,23 @@ impl Replacer {
             input = input.replace(&mp3, &replaced);
         }
         // replace `<link>` elements with the new host
-        self.replace_links(input.as_str())
+        let mut output = self.replace_links(input.as_str());
+        // make sure apps keep using the forwarder for fetching the feed
+        output = self.replace_self_link(&output);
+        if self.new_feed_url {
+            output = self.replace_new_feed_url(&output);
+        }
+        output
     }
 
     /// Dummy replacer for testing
     #[cfg(test)]
     fn dummy() -> Self {
-        Self {
-            link_url: Url::parse("http://example.com/podcast").unwrap(),
-            forward_url: Url::parse("http://test_dummy.com").unwrap(),
-            path_prefix: None,
-            link_regex: Regex::new(LINK_REGEX).unwrap(),
-            enclosure_regex: Regex::new(ENCLOSURE_URL_REGEX).unwrap(),
-        }
+        Self::new(
+            Url::parse("http://example.com/podcast").unwrap(),
+            Url::parse("http://test_dummy.com").unwrap(),
+            None,
+        )
     }
 }
 
@@ -317,16 +616,236 @@ mod tests {
         assert_eq!(output, expected);
     }
 
+    #[test]
+    fn test_replace_mp3_with_token() {
+        let old_mp3 = r#"<enclosure url="https://example.com/podcast.mp3" type="audio/mpeg" length="96950025"/>"#;
+        let key = TokenKey::new("secret");
+        let output = Replacer::new(
+            Url::parse("http://example.com/podcast").unwrap(),
+            Url::parse("http://foo.org").unwrap(),
+            Some("/r"),
+        )
+        .with_mode(Mode::Token)
+        .with_token_key(key.clone())
+        .replace(old_mp3.to_string());
+        assert!(output.starts_with(r#"<enclosure url="http://foo.org/r/podcast.mp3?fwd="#));
+        assert!(!output.contains("example.com"));
+        let forward_url = output.split('"').nth(1).unwrap();
+        assert_eq!(
+            codec::decode(forward_url, Some("/r"), Some(&key)).unwrap(),
+            Url::parse("https://example.com/podcast.mp3").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_replace_mp3_with_path() {
+        let old_mp3 = r#"<enclosure url="https://example.com/some/podcast.mp3?a=1" type="audio/mpeg" length="96950025"/>"#;
+        let new_mp3 = r#"<enclosure url="http://foo.org/media/https/example.com/some/podcast.mp3?a=1" type="audio/mpeg" length="96950025"/>"#;
+        let output = Replacer::new(
+            Url::parse("http://example.com/podcast").unwrap(),
+            Url::parse("http://foo.org").unwrap(),
+            Some("/media"),
+        )
+        .with_mode(Mode::Path)
+        .replace(old_mp3.to_string());
+        assert_eq!(output, new_mp3);
+    }
+
+    #[test]
+    fn test_replace_mp3_with_prefixes() {
+        let old_mp3 = r#"<enclosure url="https://dts.podtrac.com/redirect.mp3/chtbl.com/track/123/example.com/podcast.mp3" type="audio/mpeg" length="96950025"/>"#;
+        let kept = r#"<enclosure url="http://foo.org/r/redirect.mp3/chtbl.com/track/123/example.com/podcast.mp3?ref=https%3A%2F%2Fdts.podtrac.com%2Fredirect.mp3%2Fchtbl.com%2Ftrack%2F123%2Fexample.com%2Fpodcast.mp3" type="audio/mpeg" length="96950025"/>"#;
+        let stripped = r#"<enclosure url="http://foo.org/r/podcast.mp3?ref=https%3A%2F%2Fexample.com%2Fpodcast.mp3" type="audio/mpeg" length="96950025"/>"#;
+        let replacer = Replacer::new(
+            Url::parse("http://example.com/podcast").unwrap(),
+            Url::parse("http://foo.org").unwrap(),
+            Some("/r"),
+        );
+        assert_eq!(replacer.replace(old_mp3.to_string()), kept);
+        let replacer = replacer.with_prefixes(Policy::Strip);
+        assert_eq!(replacer.replace(old_mp3.to_string()), stripped);
+    }
+
+    #[test]
+    fn test_replace_idempotent() {
+        let input = r#"<enclosure url="https://example.com/podcast.mp3" type="audio/mpeg" length="96950025"/>"#;
+        for mode in [Mode::Ref, Mode::Token, Mode::Path] {
+            let replacer = Replacer::new(
+                Url::parse("http://example.com/podcast").unwrap(),
+                Url::parse("http://foo.org/?feed=1").unwrap(),
+                Some("/r"),
+            )
+            .with_mode(mode)
+            .with_token_key(TokenKey::new("secret"));
+            let once = replacer.replace(input.to_string());
+            assert_ne!(once, input);
+            assert_eq!(replacer.replace(once.clone()), once);
+        }
+    }
+
     #[test]
     fn test_replace_podcast_link() {
         let input = "<link>https://redcircle.com/shows/open-podcast</link>";
         let expected = "<link>https://example.org/</link>";
         let output = Replacer::new(
-            Url::parse("http://example.com/podcast").unwrap(),
             Url::parse("https://example.org").unwrap(),
+            Url::parse("http://example.com/podcast").unwrap(),
             None,
         )
         .replace(input.to_string());
         assert_eq!(output, expected);
     }
+
+    #[test]
+    fn test_replace_self_link() {
+        let input = r#"<atom:link href="https://pubsubhubbub.appspot.com/" rel="hub"/>
+<atom:link href="https://doppelgaenger.podigee.io/feed/mp3" rel="self"/>
+<atom:link rel='self' type="application/rss+xml" href='https://feeds.redcircle.com/2c2cd740'/>"#;
+        let expected = r#"<atom:link href="https://pubsubhubbub.appspot.com/" rel="hub"/>
+<atom:link href="https://example.org/feed?a=1&amp;b=2" rel="self"/>
+<atom:link rel='self' type="application/rss+xml" href="https://example.org/feed?a=1&amp;b=2"/>"#;
+        let output = Replacer::new(
+            Url::parse("http://example.com/podcast").unwrap(),
+            Url::parse("https://example.org/?foo=bar").unwrap(),
+            None,
+        )
+        .with_feed_url(Url::parse("https://example.org/feed?a=1&b=2").unwrap())
+        .replace(input.to_string());
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_self_link_fixtures() {
+        let replacer = Replacer::new(
+            Url::parse("http://example.com/podcast").unwrap(),
+            Url::parse("https://example.org/?foo=bar").unwrap(),
+            Some("/r"),
+        );
+
+        let input = include_str!("../fixtures/doppelgaenger_20220205.rss");
+        let output = replacer.replace(input.to_string());
+        assert!(output.contains(r#"<atom:link href="https://example.org/" rel="self"/>"#));
+        assert!(output.contains(
+            r#"<atom:link href="https://doppelgaenger.podigee.io/feed/mp3" rel="first"/>"#
+        ));
+        assert!(!output.contains("<itunes:new-feed-url>"));
+
+        // No self link, nothing to replace
+        let input = include_str!("../fixtures/engineering_kiosk_20220205.rss");
+        let output = replacer.replace(input.to_string());
+        assert!(!output.contains("rel=\"self\""));
+    }
+
+    #[test]
+    fn test_new_feed_url_fixtures() {
+        let replacer = Replacer::new(
+            Url::parse("http://example.com/podcast").unwrap(),
+            Url::parse("https://example.org/").unwrap(),
+            Some("/r"),
+        )
+        .with_new_feed_url(true);
+        for input in [
+            include_str!("../fixtures/doppelgaenger_20220205.rss"),
+            include_str!("../fixtures/engineering_kiosk_20220205.rss"),
+        ] {
+            let output = replacer.replace(input.to_string());
+            let new_feed_url = "<itunes:new-feed-url>https://example.org/</itunes:new-feed-url>";
+            assert_eq!(output.matches(new_feed_url).count(), 1);
+            assert!(output.find(new_feed_url).unwrap() < output.find("<item>").unwrap());
+        }
+    }
+
+    #[test]
+    fn test_override_new_feed_url() {
+        let input = "<channel><itunes:new-feed-url>https://old.example.com/feed</itunes:new-feed-url><item></item></channel>";
+        let expected = "<channel><itunes:new-feed-url>https://example.org/</itunes:new-feed-url><item></item></channel>";
+        let output = Replacer::new(
+            Url::parse("http://example.com/podcast").unwrap(),
+            Url::parse("https://example.org/").unwrap(),
+            None,
+        )
+        .with_new_feed_url(true)
+        .replace(input.to_string());
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn test_keep_item_links() {
+        let input = r"<channel>
+<link>https://redcircle.com/shows/open-podcast</link>
+<item><guid>1</guid><link>https://redcircle.com/episodes/1</link></item>
+<item><guid>2</guid><link>https://redcircle.com/episodes/2</link></item>
+</channel>";
+        let expected = r"<channel>
+<link>http://example.com/podcast</link>
+<item><guid>1</guid><link>https://redcircle.com/episodes/1</link></item>
+<item><guid>2</guid><link>https://redcircle.com/episodes/2</link></item>
+</channel>";
+        let output = Replacer::dummy().replace(input.to_string());
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    #[allow(clippy::literal_string_with_formatting_args)]
+    fn test_item_link_template() {
+        let input = r#"<channel>
+<link>https://redcircle.com/shows/open-podcast</link>
+<item><guid isPermaLink="false">a/b</guid><itunes:episode>5</itunes:episode><link>https://redcircle.com/episodes/1</link></item>
+<item><guid><![CDATA[c&d]]></guid><link>https://redcircle.com/episodes/2</link></item>
+</channel>"#;
+        let expected = r#"<channel>
+<link>http://example.com/podcast</link>
+<item><guid isPermaLink="false">a/b</guid><itunes:episode>5</itunes:episode><link>http://example.com/podcast/episodes/a%2Fb</link></item>
+<item><guid><![CDATA[c&d]]></guid><link>http://example.com/podcast/episodes/c%26d</link></item>
+</channel>"#;
+        let output = Replacer::dummy()
+            .with_item_link_template("{website}/episodes/{guid}")
+            .replace(input.to_string());
+        assert_eq!(output, expected);
+
+        // Items without the element keep their upstream link
+        let expected = r#"<channel>
+<link>http://example.com/podcast</link>
+<item><guid isPermaLink="false">a/b</guid><itunes:episode>5</itunes:episode><link>http://example.com/podcast/5</link></item>
+<item><guid><![CDATA[c&d]]></guid><link>https://redcircle.com/episodes/2</link></item>
+</channel>"#;
+        let output = Replacer::dummy()
+            .with_item_link_template("{website}/{itunes:episode}")
+            .replace(input.to_string());
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    #[allow(clippy::literal_string_with_formatting_args)]
+    fn test_item_links_fixtures() {
+        let input = include_str!("../fixtures/doppelgaenger_20220205.rss");
+        let output = Replacer::dummy().replace(input.to_string());
+        assert!(output.contains("<link>https://doppelgaenger.podigee.io/116-new-episode</link>"));
+        assert_eq!(
+            output
+                .matches("<link>http://example.com/podcast</link>")
+                .count(),
+            2
+        );
+
+        let output = Replacer::dummy()
+            .with_item_link_template("{website}/{itunes:episode}")
+            .replace(input.to_string());
+        assert!(output.contains("<link>http://example.com/podcast/116</link>"));
+        assert!(!output.contains("<link>https://doppelgaenger.podigee.io/116-new-episode</link>"));
+    }
+
+    #[test]
+    fn test_latest_episode() {
+        let feed = r#"<rss><channel><title>Podcast</title>
+            <item><title>Episode 2</title><guid isPermaLink="false"> ep-2 </guid></item>
+            <item><title>Episode 1</title><guid>ep-1</guid></item>
+            </channel></rss>"#;
+        assert_eq!(latest_episode(feed), Some("ep-2".to_string()));
+        assert_eq!(
+            latest_episode("<item><title><![CDATA[Episode 1]]></title></item>"),
+            Some("Episode 1".to_string())
+        );
+        assert_eq!(latest_episode("<rss><channel></channel></rss>"), None);
+    }
 }
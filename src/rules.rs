@@ -0,0 +1,334 @@
+//! Per-client feed customization rules
+//!
+//! Rules are configured per deployment as a JSON list in the `FEED_RULES`
+//! variable. Each rule matches on the detected podcast client and lists the
+//! actions to apply to the feed for that client.
+//!
+//! # Example
+//!
+//! ```json
+//! [
+//!   { "match": { "bot": true }, "actions": [{ "action": "block" }] },
+//!   {
+//!     "match": { "client": "Amazon Music Podcasts" },
+//!     "actions": [
+//!       { "action": "max-items", "count": 50 },
+//!       { "action": "swap-enclosure", "pattern": "\\.mp3", "replacement": "-64k.mp3" }
+//!     ]
+//!   }
+//! ]
+//! ```
+//!
+//! Like the `Replacer`, rules work on the raw feed with regular expressions
+//! instead of a full RSS parser.
+
+use crate::client::Client;
+use crate::rss::inject;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Deserializer};
+use std::sync::{Arc, LazyLock, Mutex};
+use worker::{Error, Result};
+
+/// Regex for finding items including trailing whitespace
+static ITEM_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)<item[\s>].*?</item>\s*").unwrap());
+/// Regex for finding `<enclosure>` elements
+static ENCLOSURE_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<enclosure\s[^>]*>").unwrap());
+
+/// Configuration and the rules compiled from it
+type Compiled = (String, Arc<Rules>);
+
+/// Rules compiled from the last seen configuration. The configuration only
+/// changes with a new deployment, so the regexes are compiled once per isolate.
+static CACHE: LazyLock<Mutex<Option<Compiled>>> = LazyLock::new(|| Mutex::new(None));
+
+/// Deserialize a regular expression from a string
+fn regex<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Regex, D::Error> {
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map_err(serde::de::Error::custom)
+}
+
+/// Deserialize a tag name into a regular expression matching these elements,
+/// including their content
+fn tag_regex<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Regex, D::Error> {
+    let tag = regex::escape(&String::deserialize(deserializer)?);
+    Regex::new(&format!(
+        r"(?s)<{tag}(\s[^>]*)?/>\s*|<{tag}(\s[^>]*)?>.*?</{tag}>\s*"
+    ))
+    .map_err(serde::de::Error::custom)
+}
+
+/// Conditions a client has to fulfil for a rule to apply.
+/// Conditions which are not set match every client.
+#[derive(Debug, Default, Deserialize)]
+pub struct Matcher {
+    /// Sanitized name of the client, e.g. `Spotify`
+    client: Option<String>,
+    /// Whether the client is a bot
+    bot: Option<bool>,
+}
+
+impl Matcher {
+    fn matches(&self, client: &Client) -> bool {
+        self.client
+            .as_ref()
+            .is_none_or(|name| name == client.name())
+            && self.bot.is_none_or(|bot| bot == client.is_bot())
+    }
+}
+
+/// Modification of the feed for matching clients
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum Action {
+    /// Answer with `403 Forbidden` instead of serving the feed
+    Block,
+    /// Remove all items matching the pattern
+    HideItems {
+        #[serde(deserialize_with = "regex")]
+        pattern: Regex,
+    },
+    /// Only keep the first `count` items
+    MaxItems { count: usize },
+    /// Remove all elements with the given tag name, e.g. `itunes:image`
+    StripTag {
+        #[serde(rename = "tag", deserialize_with = "tag_regex")]
+        pattern: Regex,
+    },
+    /// Insert the given XML into the channel, before the first item
+    InjectTag { xml: String },
+    /// Replace the pattern inside of all `<enclosure>` elements
+    SwapEnclosure {
+        #[serde(deserialize_with = "regex")]
+        pattern: Regex,
+        replacement: String,
+    },
+}
+
+/// A single customization rule
+#[derive(Debug, Deserialize)]
+pub struct Rule {
+    #[serde(rename = "match", default)]
+    matcher: Matcher,
+    actions: Vec<Action>,
+}
+
+/// All customization rules of a deployment
+#[derive(Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub struct Rules(Vec<Rule>);
+
+impl Rules {
+    /// Parse rules from their JSON configuration
+    ///
+    /// # Errors
+    ///
+    /// * the configuration is not valid JSON
+    /// * an action is unknown or a pattern is not a valid regex
+    pub fn from_json(config: &str) -> Result<Self> {
+        serde_json::from_str(config)
+            .map_err(|e| Error::RustError(format!("Invalid feed rules: {e}")))
+    }
+
+    /// Parse rules from their JSON configuration, reusing the rules of the
+    /// previous call if the configuration didn't change
+    ///
+    /// # Errors
+    ///
+    /// See [`Rules::from_json`]
+    pub fn cached(config: &str) -> Result<Arc<Self>> {
+        let cached = CACHE
+            .lock()
+            .unwrap()
+            .as_ref()
+            .filter(|(cached, _)| cached == config)
+            .map(|(_, rules)| Arc::clone(rules));
+        if let Some(rules) = cached {
+            return Ok(rules);
+        }
+        let rules = Arc::new(Self::from_json(config)?);
+        *CACHE.lock().unwrap() = Some((config.to_string(), Arc::clone(&rules)));
+        Ok(rules)
+    }
+
+    /// All actions of rules which match the given client, in order
+    fn actions<'a>(&'a self, client: &'a Client) -> impl Iterator<Item = &'a Action> {
+        self.0
+            .iter()
+            .filter(|rule| rule.matcher.matches(client))
+            .flat_map(|rule| rule.actions.iter())
+    }
+
+    /// Check if the client should not get the feed at all
+    pub fn blocks(&self, client: &Client) -> bool {
+        self.actions(client)
+            .any(|action| matches!(action, Action::Block))
+    }
+
+    /// Apply all actions for the given client to the feed
+    #[must_use]
+    pub fn apply(&self, client: &Client, mut feed: String) -> String {
+        for action in self.actions(client) {
+            feed = match action {
+                Action::Block => feed,
+                Action::HideItems { pattern } => ITEM_REGEX
+                    .replace_all(&feed, |caps: &Captures| {
+                        if pattern.is_match(&caps[0]) {
+                            String::new()
+                        } else {
+                            caps[0].to_string()
+                        }
+                    })
+                    .to_string(),
+                Action::MaxItems { count } => {
+                    let mut seen = 0;
+                    ITEM_REGEX
+                        .replace_all(&feed, |caps: &Captures| {
+                            seen += 1;
+                            if seen > *count {
+                                String::new()
+                            } else {
+                                caps[0].to_string()
+                            }
+                        })
+                        .to_string()
+                }
+                Action::StripTag { pattern } => pattern.replace_all(&feed, "").to_string(),
+                Action::InjectTag { xml } => inject(&feed, xml),
+                Action::SwapEnclosure {
+                    pattern,
+                    replacement,
+                } => ENCLOSURE_REGEX
+                    .replace_all(&feed, |caps: &Captures| {
+                        pattern
+                            .replace_all(&caps[0], replacement.as_str())
+                            .to_string()
+                    })
+                    .to_string(),
+            };
+        }
+        feed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientCategory;
+
+    use pretty_assertions::assert_eq;
+
+    const DOPPELGAENGER: &str = include_str!("../fixtures/doppelgaenger_20220205.rss");
+    const ENGINEERING_KIOSK: &str = include_str!("../fixtures/engineering_kiosk_20220205.rss");
+
+    fn count(feed: &str, pattern: &str) -> usize {
+        Regex::new(pattern).unwrap().find_iter(feed).count()
+    }
+
+    #[test]
+    fn test_matcher() {
+        let rules = Rules::from_json(
+            r#"[
+                { "match": { "client": "Spotify" }, "actions": [{ "action": "block" }] },
+                { "match": { "bot": true }, "actions": [{ "action": "block" }] }
+            ]"#,
+        )
+        .unwrap();
+        assert!(rules.blocks(&Client::new("Spotify", ClientCategory::PodcastApp)));
+        assert!(rules.blocks(&Client::new("Script", ClientCategory::Script)));
+        assert!(!rules.blocks(&Client::new("Overcast", ClientCategory::PodcastApp)));
+        assert!(!Rules::default().blocks(&Client::new("Spotify", ClientCategory::PodcastApp)));
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        assert!(Rules::from_json(r#"[{ "actions": [{ "action": "lol" }] }]"#).is_err());
+        assert!(Rules::from_json(
+            r#"[{ "actions": [{ "action": "hide-items", "pattern": "(" }] }]"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_cached() {
+        let config = r#"[{ "actions": [{ "action": "block" }] }]"#;
+        let rules = Rules::cached(config).unwrap();
+        assert!(Arc::ptr_eq(&rules, &Rules::cached(config).unwrap()));
+        assert!(!Arc::ptr_eq(&rules, &Rules::cached("[]").unwrap()));
+        assert!(Rules::cached("lol").is_err());
+    }
+
+    #[test]
+    fn test_max_items() {
+        let rules = Rules::from_json(r#"[{ "actions": [{ "action": "max-items", "count": 3 }] }]"#)
+            .unwrap();
+        let output = rules.apply(
+            &Client::new("Spotify", ClientCategory::PodcastApp),
+            DOPPELGAENGER.to_string(),
+        );
+        assert_eq!(count(DOPPELGAENGER, "<item>"), 117);
+        assert_eq!(count(&output, "<item>"), 3);
+        assert_eq!(count(&output, "</channel>"), 1);
+    }
+
+    #[test]
+    fn test_hide_items() {
+        let rules = Rules::from_json(
+            r#"[{ "match": { "client": "Spotify" }, "actions": [{ "action": "hide-items", "pattern": "<itunes:episode>5</itunes:episode>" }] }]"#,
+        )
+        .unwrap();
+        let output = rules.apply(
+            &Client::new("Spotify", ClientCategory::PodcastApp),
+            ENGINEERING_KIOSK.to_string(),
+        );
+        assert_eq!(count(&output, "<item>"), 4);
+        assert!(!output.contains("<itunes:episode>5</itunes:episode>"));
+
+        let output = rules.apply(
+            &Client::new("Overcast", ClientCategory::PodcastApp),
+            ENGINEERING_KIOSK.to_string(),
+        );
+        assert_eq!(output, ENGINEERING_KIOSK);
+    }
+
+    #[test]
+    fn test_strip_and_inject_tags() {
+        let rules = Rules::from_json(
+            r#"[{ "actions": [
+                { "action": "strip-tag", "tag": "itunes:image" },
+                { "action": "inject-tag", "xml": "<podcast:locked>yes</podcast:locked>" }
+            ] }]"#,
+        )
+        .unwrap();
+        let output = rules.apply(
+            &Client::new("Spotify", ClientCategory::PodcastApp),
+            ENGINEERING_KIOSK.to_string(),
+        );
+        assert_eq!(count(&output, "<itunes:image"), 0);
+        assert_eq!(count(&output, "<podcast:locked>yes</podcast:locked>"), 1);
+        assert!(
+            output.find("<podcast:locked>").unwrap() < output.find("<item>").unwrap(),
+            "tag should be injected before the first item"
+        );
+    }
+
+    #[test]
+    fn test_swap_enclosure() {
+        let rules = Rules::from_json(
+            r#"[{ "match": { "client": "Amazon Music Podcasts" }, "actions": [
+                { "action": "swap-enclosure", "pattern": "/stream\\.mp3", "replacement": "/stream-64k.mp3" }
+            ] }]"#,
+        )
+        .unwrap();
+        let output = rules.apply(
+            &Client::new("Amazon Music Podcasts", ClientCategory::PodcastApp),
+            ENGINEERING_KIOSK.to_string(),
+        );
+        assert_eq!(
+            count(&output, "/stream-64k.mp3"),
+            count(ENGINEERING_KIOSK, r"<enclosure[^>]*/stream\.mp3")
+        );
+        assert!(count(&output, "/stream-64k.mp3") > 0);
+    }
+}
@@ -0,0 +1,614 @@
+//! Daily subscriber estimate
+//!
+//! Feed readers like Feedly or Inoreader fetch the feed once for all of their
+//! users and put the number of subscribers into their user agent, e.g.
+//! `Feedly/1.0 (+http://www.feedly.com/fetcher.html; 42 subscribers)`.
+//! Podcast apps poll the feed directly, so every combination of client and IP
+//! counts as one subscriber. The estimate for a day is the sum of both.
+//!
+//! Estimates are kept in the `Subscribers` Durable Object, so they cover all
+//! isolates. Direct pollers are only stored as SHA-256 hashes of client and IP
+//! with a random salt of the day. The salt gets replaced every day and is
+//! never stored elsewhere, so pollers can't be linked across days or traced
+//! back to their IP.
+//!
+//! Apps poll the feed many times a day, but only the first poll of a day
+//! counts. Every isolate remembers the fetches it recorded today, so repeated
+//! polls don't reach the Durable Object.
+
+use crate::client::Client;
+use crate::durable;
+use crate::time::{date, DAY};
+use lru::LruCache;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+#[cfg(test)]
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::{LazyLock, Mutex};
+use uuid::Uuid;
+use worker::{
+    async_trait, durable_object, js_sys, wasm_bindgen, wasm_bindgen_futures, worker_sys, Env,
+    ListOptions, Request, Response, Result, State,
+};
+
+/// Binding of the `Subscribers` Durable Object in `wrangler.toml`
+const BINDING: &str = "SUBSCRIBERS";
+
+/// Name of the single `Subscribers` Durable Object
+const NAME: &str = "subscribers";
+
+/// Prefix of the storage keys of days
+const DAY_PREFIX: &str = "day:";
+
+/// Prefix of the storage keys of pollers
+const POLLER_PREFIX: &str = "poller:";
+
+/// Storage key of the salt of the current day
+const SALT_KEY: &str = "salt";
+
+/// Number of poller keys of past days which get deleted per recorded fetch.
+/// Durable Objects delete at most 128 keys at once.
+const EXPIRE_CHUNK: usize = 128;
+
+/// Number of days for which estimates are kept
+const MAX_DAYS: u64 = 7;
+
+/// Maximum number of direct pollers counted per day
+const MAX_POLLERS: u64 = 100_000;
+
+/// Number of recorded fetches each isolate remembers
+const RECORDED_SIZE: usize = 10_000;
+
+/// Subscriber count in the user agent of a feed reader, e.g. `42 subscribers`
+/// or `1 reader`
+static COUNT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?i)\b(\d+)\s+(?:subscribers?|readers?)\b").unwrap());
+
+/// Fetches this isolate recorded, see `Fetch::recorded_key`. They are only
+/// kept in memory
+static RECORDED: LazyLock<Mutex<LruCache<Recorded, ()>>> =
+    LazyLock::new(|| Mutex::new(LruCache::new(NonZeroUsize::new(RECORDED_SIZE).unwrap())));
+
+/// Day, client, IP and subscriber count of a recorded fetch
+type Recorded = (u64, String, Option<IpAddr>, Option<u64>);
+
+/// Parse the subscriber count from the user agent of a feed reader
+#[must_use]
+pub fn count(user_agent: &str) -> Option<u64> {
+    COUNT.captures(user_agent)?.get(1)?.as_str().parse().ok()
+}
+
+/// Hash of a direct poller, see the module documentation
+#[must_use]
+fn poller(salt: &str, client: &str, ip: Option<IpAddr>) -> String {
+    let ip = ip.map(|ip| ip.to_string()).unwrap_or_default();
+    let hash = Sha256::new()
+        .chain_update(salt)
+        .chain_update([0])
+        .chain_update(client)
+        .chain_update([0])
+        .chain_update(ip)
+        .finalize();
+    base64::encode_config(hash, base64::URL_SAFE_NO_PAD)
+}
+
+/// Feed fetch which counts towards the estimate
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fetch {
+    /// Name of the client
+    client: String,
+    /// Number of subscribers reported by a feed reader
+    subscribers: Option<u64>,
+    /// IP of the client
+    ip: Option<IpAddr>,
+    /// Time of the fetch in milliseconds
+    now: u64,
+}
+
+impl Fetch {
+    /// Feed fetch of `client` from `ip` at time `now` (in milliseconds).
+    /// Bots without a subscriber count don't count.
+    #[must_use]
+    pub fn new(client: &Client, ip: Option<IpAddr>, now: u64) -> Option<Self> {
+        if client.subscribers().is_none() && client.is_bot() {
+            return None;
+        }
+        Some(Self {
+            client: client.name().to_string(),
+            subscribers: client.subscribers(),
+            ip,
+            now,
+        })
+    }
+
+    /// Key of the fetch in the fetches this isolate recorded. Fetches with
+    /// the same key don't change the estimate
+    fn recorded_key(&self) -> Recorded {
+        (
+            self.now / DAY,
+            self.client.clone(),
+            self.ip,
+            self.subscribers,
+        )
+    }
+}
+
+/// Feed fetches of a single day
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Day {
+    /// Last subscriber count reported by each feed reader
+    aggregators: BTreeMap<String, u64>,
+    /// Number of distinct direct pollers
+    pollers: u64,
+}
+
+/// Subscriber estimate for a day
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Estimate {
+    /// Day of the estimate, e.g. `2022-10-18`
+    pub date: String,
+    /// Estimated number of subscribers
+    pub subscribers: u64,
+    /// Subscribers reported by feed readers
+    pub aggregators: BTreeMap<String, u64>,
+    /// Distinct clients polling the feed directly
+    pub direct: u64,
+}
+
+/// Storage of the days, the pollers and the salt
+#[async_trait::async_trait(?Send)]
+pub trait Storage {
+    /// Load the salt and the day it belongs to
+    async fn salt(&self) -> Result<Option<(u64, String)>>;
+    /// Replace the salt
+    async fn store_salt(&mut self, day: u64, salt: &str) -> Result<()>;
+    /// Load a day
+    async fn load(&self, day: u64) -> Result<Option<Day>>;
+    /// Store a day
+    async fn store(&mut self, day: u64, fetches: &Day) -> Result<()>;
+    /// All stored days, in any order
+    async fn days(&self) -> Result<Vec<(u64, Day)>>;
+    /// Check if the poller was seen on the given day
+    async fn has_poller(&self, day: u64, poller: &str) -> Result<bool>;
+    /// Remember that the poller was seen on the given day
+    async fn insert_poller(&mut self, day: u64, poller: &str) -> Result<()>;
+    /// Delete days before `days_before` and (some) pollers before
+    /// `pollers_before`
+    async fn expire(&mut self, days_before: u64, pollers_before: u64) -> Result<()>;
+}
+
+/// Days and pollers kept in memory, for tests
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    salt: Option<(u64, String)>,
+    days: BTreeMap<u64, Day>,
+    pollers: HashSet<(u64, String)>,
+}
+
+#[cfg(test)]
+#[async_trait::async_trait(?Send)]
+impl Storage for MemoryStorage {
+    async fn salt(&self) -> Result<Option<(u64, String)>> {
+        Ok(self.salt.clone())
+    }
+
+    async fn store_salt(&mut self, day: u64, salt: &str) -> Result<()> {
+        self.salt = Some((day, salt.to_string()));
+        Ok(())
+    }
+
+    async fn load(&self, day: u64) -> Result<Option<Day>> {
+        Ok(self.days.get(&day).cloned())
+    }
+
+    async fn store(&mut self, day: u64, fetches: &Day) -> Result<()> {
+        self.days.insert(day, fetches.clone());
+        Ok(())
+    }
+
+    async fn days(&self) -> Result<Vec<(u64, Day)>> {
+        Ok(self.days.clone().into_iter().collect())
+    }
+
+    async fn has_poller(&self, day: u64, poller: &str) -> Result<bool> {
+        Ok(self.pollers.contains(&(day, poller.to_string())))
+    }
+
+    async fn insert_poller(&mut self, day: u64, poller: &str) -> Result<()> {
+        self.pollers.insert((day, poller.to_string()));
+        Ok(())
+    }
+
+    async fn expire(&mut self, days_before: u64, pollers_before: u64) -> Result<()> {
+        self.days.retain(|&day, _| day >= days_before);
+        self.pollers.retain(|(day, _)| *day >= pollers_before);
+        Ok(())
+    }
+}
+
+/// Days and pollers in the storage of the `Subscribers` Durable Object
+///
+/// Days are zero-padded in keys, so they sort in order.
+pub struct DurableStorage(worker::Storage);
+
+#[async_trait::async_trait(?Send)]
+impl Storage for DurableStorage {
+    async fn salt(&self) -> Result<Option<(u64, String)>> {
+        durable::get(&self.0, SALT_KEY).await
+    }
+
+    async fn store_salt(&mut self, day: u64, salt: &str) -> Result<()> {
+        self.0.put(SALT_KEY, (day, salt)).await
+    }
+
+    async fn load(&self, day: u64) -> Result<Option<Day>> {
+        durable::get(&self.0, &format!("{DAY_PREFIX}{day:08}")).await
+    }
+
+    async fn store(&mut self, day: u64, fetches: &Day) -> Result<()> {
+        self.0.put(&format!("{DAY_PREFIX}{day:08}"), fetches).await
+    }
+
+    async fn days(&self) -> Result<Vec<(u64, Day)>> {
+        let listing = self
+            .0
+            .list_with_options(ListOptions::new().prefix(DAY_PREFIX))
+            .await?;
+        Ok(durable::entries(&listing)
+            .into_iter()
+            .filter_map(|(key, fetches)| {
+                let day = key.strip_prefix(DAY_PREFIX)?.parse().ok()?;
+                Some((day, fetches))
+            })
+            .collect())
+    }
+
+    async fn has_poller(&self, day: u64, poller: &str) -> Result<bool> {
+        let seen: Option<bool> =
+            durable::get(&self.0, &format!("{POLLER_PREFIX}{day:08}:{poller}")).await?;
+        Ok(seen.is_some())
+    }
+
+    async fn insert_poller(&mut self, day: u64, poller: &str) -> Result<()> {
+        self.0
+            .put(&format!("{POLLER_PREFIX}{day:08}:{poller}"), true)
+            .await
+    }
+
+    async fn expire(&mut self, days_before: u64, pollers_before: u64) -> Result<()> {
+        let end = format!("{DAY_PREFIX}{days_before:08}");
+        let days = self
+            .0
+            .list_with_options(ListOptions::new().prefix(DAY_PREFIX).end(&end))
+            .await?;
+        let end = format!("{POLLER_PREFIX}{pollers_before:08}");
+        let pollers = self
+            .0
+            .list_with_options(
+                ListOptions::new()
+                    .prefix(POLLER_PREFIX)
+                    .end(&end)
+                    .limit(EXPIRE_CHUNK),
+            )
+            .await?;
+        for listing in [days, pollers] {
+            let keys: Vec<String> = listing
+                .keys()
+                .into_iter()
+                .flatten()
+                .filter_map(|key| key.as_string())
+                .collect();
+            if !keys.is_empty() {
+                self.0.delete_multiple(keys).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Subscriber estimates of the last days
+#[derive(Debug, Default)]
+pub struct Estimator<S> {
+    storage: S,
+}
+
+impl<S: Storage> Estimator<S> {
+    /// Create an estimator with the given storage
+    pub const fn new(storage: S) -> Self {
+        Self { storage }
+    }
+
+    /// Salt of the given day. A new random salt replaces the one of the
+    /// previous day. Salts of past days are gone
+    async fn salt(&mut self, day: u64) -> Result<Option<String>> {
+        match self.storage.salt().await? {
+            Some((salt_day, salt)) if salt_day == day => return Ok(Some(salt)),
+            Some((salt_day, _)) if salt_day > day => return Ok(None),
+            _ => {}
+        }
+        let salt = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        self.storage.store_salt(day, &salt).await?;
+        Ok(Some(salt))
+    }
+
+    /// Record a feed fetch
+    ///
+    /// # Errors
+    ///
+    /// * the storage fails
+    pub async fn record(&mut self, fetch: &Fetch) -> Result<()> {
+        let day = fetch.now / DAY;
+        let mut fetches = self.storage.load(day).await?.unwrap_or_default();
+        if let Some(subscribers) = fetch.subscribers {
+            fetches
+                .aggregators
+                .insert(fetch.client.clone(), subscribers);
+        } else if fetches.pollers < MAX_POLLERS {
+            // Late fetches of past days can't be told apart from known pollers
+            let Some(salt) = self.salt(day).await? else {
+                return Ok(());
+            };
+            let poller = poller(&salt, &fetch.client, fetch.ip);
+            if !self.storage.has_poller(day, &poller).await? {
+                self.storage.insert_poller(day, &poller).await?;
+                fetches.pollers += 1;
+            }
+        }
+        self.storage.store(day, &fetches).await?;
+        // Pollers are only needed on their own day
+        self.storage
+            .expire((day + 1).saturating_sub(MAX_DAYS), day)
+            .await
+    }
+
+    /// Estimates of the last `limit` days, most recent first
+    ///
+    /// # Errors
+    ///
+    /// * the storage fails
+    pub async fn estimates(&self, limit: usize) -> Result<Vec<Estimate>> {
+        let mut days = self.storage.days().await?;
+        days.sort_by(|(a, _), (b, _)| b.cmp(a));
+        Ok(days
+            .into_iter()
+            .take(limit)
+            .map(|(day, fetches)| Estimate {
+                date: date(day),
+                subscribers: fetches.pollers + fetches.aggregators.values().sum::<u64>(),
+                aggregators: fetches.aggregators,
+                direct: fetches.pollers,
+            })
+            .collect())
+    }
+}
+
+/// Requests sent to the `Subscribers` Durable Object
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Command {
+    /// Record a feed fetch
+    Record { fetch: Fetch },
+    /// Return the estimates of the last `limit` days
+    Estimates { limit: usize },
+}
+
+/// Durable Object holding the subscriber estimates
+#[durable_object]
+pub struct Subscribers {
+    state: State,
+}
+
+#[durable_object]
+impl DurableObject for Subscribers {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
+
+    // `&mut self` is required by `DurableObject`
+    #[allow(clippy::needless_pass_by_ref_mut)]
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let mut estimator = Estimator::new(DurableStorage(self.state.storage()));
+        match req.json().await? {
+            Command::Record { fetch } => {
+                estimator.record(&fetch).await?;
+                Response::from_json(&())
+            }
+            Command::Estimates { limit } => Response::from_json(&estimator.estimates(limit).await?),
+        }
+    }
+}
+
+/// Record a feed fetch. Fetches this isolate recorded before are skipped
+///
+/// # Errors
+///
+/// * the `Subscribers` Durable Object can't be reached
+pub async fn record(env: &Env, fetch: Fetch) -> Result<()> {
+    let key = fetch.recorded_key();
+    if RECORDED
+        .lock()
+        .is_ok_and(|recorded| recorded.contains(&key))
+    {
+        return Ok(());
+    }
+    durable::call::<_, ()>(env, BINDING, NAME, "/record", &Command::Record { fetch }).await?;
+    if let Ok(mut recorded) = RECORDED.lock() {
+        recorded.put(key, ());
+    }
+    Ok(())
+}
+
+/// Estimates of the last `limit` days, most recent first
+///
+/// # Errors
+///
+/// * the `Subscribers` Durable Object can't be reached
+pub async fn estimates(env: &Env, limit: usize) -> Result<Vec<Estimate>> {
+    durable::call(
+        env,
+        BINDING,
+        NAME,
+        "/estimates",
+        &Command::Estimates { limit },
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientCategory;
+
+    use futures_executor::block_on;
+    use pretty_assertions::assert_eq;
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        ip.parse().ok()
+    }
+
+    fn record(
+        estimator: &mut Estimator<MemoryStorage>,
+        client: &Client,
+        ip: Option<IpAddr>,
+        now: u64,
+    ) {
+        if let Some(fetch) = Fetch::new(client, ip, now) {
+            block_on(estimator.record(&fetch)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_count() {
+        assert_eq!(
+            count("Feedly/1.0 (+http://www.feedly.com/fetcher.html; 42 subscribers)"),
+            Some(42)
+        );
+        assert_eq!(
+            count("NewsBlur Feed Fetcher - 1 subscriber - http://www.newsblur.com/site/1"),
+            Some(1)
+        );
+        assert_eq!(
+            count("Inoreader/1.0 (+http://www.inoreader.com/feed-fetcher; 12 subscribers; )"),
+            Some(12)
+        );
+        assert_eq!(
+            count("Feedly/1.0 (+http://www.feedly.com/fetcher.html)"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_poller() {
+        let poller_ip = ip("192.0.2.1");
+        assert_eq!(
+            poller("salt", "Overcast", poller_ip),
+            poller("salt", "Overcast", poller_ip)
+        );
+        assert_eq!(poller("salt", "Overcast", poller_ip).len(), 43);
+        assert_ne!(
+            poller("salt", "Overcast", poller_ip),
+            poller("other", "Overcast", poller_ip)
+        );
+        assert_ne!(
+            poller("salt", "Overcast", poller_ip),
+            poller("salt", "Overcast", ip("192.0.2.2"))
+        );
+    }
+
+    #[test]
+    fn test_record() {
+        let mut estimator = Estimator::new(MemoryStorage::default());
+        let feedly = |subscribers| {
+            Client::new("Feedly", ClientCategory::Aggregator).with_subscribers(Some(subscribers))
+        };
+        let overcast = Client::new("Overcast", ClientCategory::PodcastApp);
+        let today = 19_283 * DAY;
+
+        // Yesterday
+        record(&mut estimator, &overcast, ip("192.0.2.1"), today - 1);
+        record(&mut estimator, &feedly(40), None, today);
+        record(&mut estimator, &feedly(42), None, today + 1000);
+        record(&mut estimator, &overcast, ip("192.0.2.1"), today);
+        record(&mut estimator, &overcast, ip("192.0.2.1"), today + 1000);
+        record(&mut estimator, &overcast, ip("192.0.2.2"), today);
+        // Bots don't subscribe
+        record(
+            &mut estimator,
+            &Client::new("Script", ClientCategory::Script),
+            ip("192.0.2.3"),
+            today,
+        );
+
+        assert_eq!(
+            block_on(estimator.estimates(10)).unwrap(),
+            vec![
+                Estimate {
+                    date: "2022-10-18".to_string(),
+                    subscribers: 44,
+                    aggregators: BTreeMap::from([("Feedly".to_string(), 42)]),
+                    direct: 2,
+                },
+                Estimate {
+                    date: "2022-10-17".to_string(),
+                    subscribers: 1,
+                    aggregators: BTreeMap::new(),
+                    direct: 1,
+                },
+            ]
+        );
+        assert_eq!(block_on(estimator.estimates(1)).unwrap().len(), 1);
+        // Only pollers and salt of today are kept
+        let storage = &estimator.storage;
+        assert_eq!(storage.pollers.len(), 2);
+        assert!(storage.pollers.iter().all(|(day, _)| *day == today / DAY));
+        assert_eq!(storage.salt.as_ref().unwrap().0, today / DAY);
+        // Fetches of yesterday arriving late are not counted anymore
+        record(&mut estimator, &overcast, ip("192.0.2.4"), today - 1);
+        assert_eq!(block_on(estimator.estimates(2)).unwrap()[1].direct, 1);
+    }
+
+    #[test]
+    fn test_recorded_key() {
+        let overcast = Client::new("Overcast", ClientCategory::PodcastApp);
+        let feedly = |subscribers| {
+            Client::new("Feedly", ClientCategory::Aggregator).with_subscribers(Some(subscribers))
+        };
+        let key = |client: &Client, ip, now| Fetch::new(client, ip, now).unwrap().recorded_key();
+        let today = 19_283 * DAY;
+
+        // Polls of the same day don't change the estimate
+        assert_eq!(
+            key(&overcast, ip("192.0.2.1"), today),
+            key(&overcast, ip("192.0.2.1"), today + 1000)
+        );
+        assert_ne!(
+            key(&overcast, ip("192.0.2.1"), today),
+            key(&overcast, ip("192.0.2.1"), today + DAY)
+        );
+        assert_ne!(
+            key(&overcast, ip("192.0.2.1"), today),
+            key(&overcast, ip("192.0.2.2"), today)
+        );
+        // New subscriber counts do
+        assert_ne!(key(&feedly(42), None, today), key(&feedly(43), None, today));
+    }
+
+    #[test]
+    fn test_record_bounded() {
+        let mut estimator = Estimator::new(MemoryStorage::default());
+        let overcast = Client::new("Overcast", ClientCategory::PodcastApp);
+        for day in 0..=MAX_DAYS {
+            record(&mut estimator, &overcast, None, day * DAY);
+        }
+        assert_eq!(estimator.storage.days.len() as u64, MAX_DAYS);
+        assert_eq!(
+            block_on(estimator.estimates(1)).unwrap()[0].date,
+            date(MAX_DAYS)
+        );
+    }
+}
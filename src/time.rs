@@ -0,0 +1,61 @@
+//! Formatting of timestamps without a date library
+
+/// Length of a day in milliseconds
+pub const DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// Format the day with the given number since the epoch as `YYYY-MM-DD`
+///
+/// See <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+#[must_use]
+pub fn date(day: u64) -> String {
+    let z = day + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let d = day_of_year - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = year_of_era + era * 400 + u64::from(m <= 2);
+    format!("{y:04}-{m:02}-{d:02}")
+}
+
+/// Format a timestamp in milliseconds since the epoch as RFC 3339 in UTC
+/// with millisecond precision, e.g. `2022-10-18T09:30:00.123Z`
+#[must_use]
+pub fn rfc3339(millis: u64) -> String {
+    let time = millis % DAY;
+    format!(
+        "{}T{:02}:{:02}:{:02}.{:03}Z",
+        date(millis / DAY),
+        time / 3_600_000,
+        time / 60_000 % 60,
+        time / 1000 % 60,
+        time % 1000
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_date() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(19_283), "2022-10-18");
+        assert_eq!(date(11_016), "2000-02-29");
+    }
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!(rfc3339(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            rfc3339(19_283 * DAY + 9 * 3_600_000 + 30 * 60_000 + 5_123),
+            "2022-10-18T09:30:05.123Z"
+        );
+        assert_eq!(rfc3339(DAY - 1), "1970-01-01T23:59:59.999Z");
+    }
+}
@@ -0,0 +1,375 @@
+//! Collection of unknown user agents
+//!
+//! User agents which are not in the lookup table of `client` get normalized
+//! and counted, so the most common ones can be added to the table. Counts are
+//! kept in the `UnknownUserAgents` Durable Object, so they cover all isolates
+//! and survive deploys.
+
+use crate::durable;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+#[cfg(test)]
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use worker::{
+    async_trait, durable_object, js_sys, wasm_bindgen, wasm_bindgen_futures, worker_sys, Env,
+    ListOptions, Request, Response, Result, State,
+};
+
+/// Binding of the `UnknownUserAgents` Durable Object in `wrangler.toml`
+const BINDING: &str = "UNKNOWN_USER_AGENTS";
+
+/// Name of the single `UnknownUserAgents` Durable Object
+const NAME: &str = "unknown-user-agents";
+
+/// Prefix of the storage keys of entries
+const ENTRY_PREFIX: &str = "ua:";
+
+/// Storage key of the number of entries
+const LEN_KEY: &str = "len";
+
+/// Maximum number of distinct user agents which get counted. User agents
+/// seen for the first time are ignored beyond that.
+const MAX_USER_AGENTS: usize = 1000;
+
+/// Maximum length of a normalized user agent
+const MAX_LENGTH: usize = 200;
+
+/// Parts of user agents which differ between devices or versions of the
+/// same client: parenthesized device information, UUIDs, long hex ids and
+/// version numbers
+static VARIABLE_PARTS: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?x)
+        \([^)]*\)
+        | [0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}
+        | \b[0-9a-fA-F]{16,}\b
+        | \b[vV]?\d+(?:[._,]\d+)*\b
+        ",
+    )
+    .unwrap()
+});
+
+/// Runs of whitespace
+static WHITESPACE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\s+").unwrap());
+
+/// Normalize a user agent, so different versions and devices of the same
+/// client are counted together, e.g. `MyCast/2.3.1 (iPhone12,1; iOS 15.1)`
+/// becomes `MyCast/`
+#[must_use]
+pub fn normalize(user_agent: &str) -> String {
+    let stripped = VARIABLE_PARTS.replace_all(user_agent, " ");
+    let mut normalized = WHITESPACE.replace_all(stripped.trim(), " ").to_string();
+    if normalized.len() > MAX_LENGTH {
+        let end = (0..=MAX_LENGTH)
+            .rev()
+            .find(|&i| normalized.is_char_boundary(i))
+            .unwrap_or_default();
+        normalized.truncate(end);
+    }
+    normalized
+}
+
+/// Normalized unknown user agent and how often it was seen
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Normalized user agent
+    pub user_agent: String,
+    /// Number of requests
+    pub count: u64,
+    /// Last original user agent, useful for reviewing the entry
+    pub example: String,
+}
+
+impl Entry {
+    /// Suggested entry in the format of the OPAWG user agent list
+    /// (<https://github.com/opawg/user-agents>)
+    #[must_use]
+    pub fn opawg(&self) -> Value {
+        let app = self
+            .user_agent
+            .split(['/', ' '])
+            .find(|part| !part.is_empty())
+            .unwrap_or(&self.user_agent);
+        json!({
+            "user_agents": [format!("^{}", regex::escape(&self.user_agent))],
+            "app": app,
+            "examples": [self.example],
+        })
+    }
+}
+
+/// Storage of the entries
+#[async_trait::async_trait(?Send)]
+pub trait Storage {
+    /// Load the entry of a normalized user agent
+    async fn load(&self, user_agent: &str) -> Result<Option<Entry>>;
+    /// Add an entry which wasn't stored before
+    async fn insert(&mut self, entry: &Entry) -> Result<()>;
+    /// Replace a stored entry
+    async fn update(&mut self, entry: &Entry) -> Result<()>;
+    /// Number of stored entries
+    async fn len(&self) -> Result<usize>;
+    /// All stored entries
+    async fn entries(&self) -> Result<Vec<Entry>>;
+}
+
+/// Entries kept in memory, for tests
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MemoryStorage(HashMap<String, Entry>);
+
+#[cfg(test)]
+#[async_trait::async_trait(?Send)]
+impl Storage for MemoryStorage {
+    async fn load(&self, user_agent: &str) -> Result<Option<Entry>> {
+        Ok(self.0.get(user_agent).cloned())
+    }
+
+    async fn insert(&mut self, entry: &Entry) -> Result<()> {
+        self.0.insert(entry.user_agent.clone(), entry.clone());
+        Ok(())
+    }
+
+    async fn update(&mut self, entry: &Entry) -> Result<()> {
+        self.insert(entry).await
+    }
+
+    async fn len(&self) -> Result<usize> {
+        Ok(self.0.len())
+    }
+
+    async fn entries(&self) -> Result<Vec<Entry>> {
+        Ok(self.0.values().cloned().collect())
+    }
+}
+
+/// Entries in the storage of the `UnknownUserAgents` Durable Object, one key
+/// per entry plus the number of entries
+pub struct DurableStorage(worker::Storage);
+
+#[async_trait::async_trait(?Send)]
+impl Storage for DurableStorage {
+    async fn load(&self, user_agent: &str) -> Result<Option<Entry>> {
+        durable::get(&self.0, &format!("{ENTRY_PREFIX}{user_agent}")).await
+    }
+
+    async fn insert(&mut self, entry: &Entry) -> Result<()> {
+        let len = self.len().await?;
+        self.update(entry).await?;
+        self.0.put(LEN_KEY, len + 1).await
+    }
+
+    async fn update(&mut self, entry: &Entry) -> Result<()> {
+        self.0
+            .put(&format!("{ENTRY_PREFIX}{}", entry.user_agent), entry)
+            .await
+    }
+
+    async fn len(&self) -> Result<usize> {
+        Ok(durable::get(&self.0, LEN_KEY).await?.unwrap_or_default())
+    }
+
+    async fn entries(&self) -> Result<Vec<Entry>> {
+        let listing = self
+            .0
+            .list_with_options(ListOptions::new().prefix(ENTRY_PREFIX))
+            .await?;
+        Ok(durable::entries(&listing)
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .collect())
+    }
+}
+
+/// Counts of normalized unknown user agents
+#[derive(Debug, Default)]
+pub struct Collector<S> {
+    storage: S,
+}
+
+impl<S: Storage> Collector<S> {
+    /// Create a collector with the given storage
+    pub const fn new(storage: S) -> Self {
+        Self { storage }
+    }
+
+    /// Count a request with the given unknown user agent
+    ///
+    /// # Errors
+    ///
+    /// * the storage fails
+    pub async fn record(&mut self, user_agent: &str) -> Result<()> {
+        let normalized = normalize(user_agent);
+        if normalized.is_empty() {
+            return Ok(());
+        }
+        if let Some(mut entry) = self.storage.load(&normalized).await? {
+            entry.count += 1;
+            entry.example = user_agent.to_string();
+            self.storage.update(&entry).await
+        } else if self.storage.len().await? < MAX_USER_AGENTS {
+            self.storage
+                .insert(&Entry {
+                    user_agent: normalized,
+                    count: 1,
+                    example: user_agent.to_string(),
+                })
+                .await
+        } else {
+            Ok(())
+        }
+    }
+
+    /// The `limit` most common unknown user agents
+    ///
+    /// # Errors
+    ///
+    /// * the storage fails
+    pub async fn top(&self, limit: usize) -> Result<Vec<Entry>> {
+        let mut entries = self.storage.entries().await?;
+        entries.sort_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then_with(|| a.user_agent.cmp(&b.user_agent))
+        });
+        entries.truncate(limit);
+        Ok(entries)
+    }
+}
+
+/// Requests sent to the `UnknownUserAgents` Durable Object
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Command {
+    /// Count a request with the given unknown user agent
+    Record { user_agent: String },
+    /// Return the `limit` most common unknown user agents
+    Top { limit: usize },
+}
+
+/// Durable Object holding the counts of all unknown user agents
+#[durable_object]
+pub struct UnknownUserAgents {
+    state: State,
+}
+
+#[durable_object]
+impl DurableObject for UnknownUserAgents {
+    fn new(state: State, _env: Env) -> Self {
+        Self { state }
+    }
+
+    // `&mut self` is required by `DurableObject`
+    #[allow(clippy::needless_pass_by_ref_mut)]
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let mut collector = Collector::new(DurableStorage(self.state.storage()));
+        match req.json().await? {
+            Command::Record { user_agent } => {
+                collector.record(&user_agent).await?;
+                Response::from_json(&())
+            }
+            Command::Top { limit } => Response::from_json(&collector.top(limit).await?),
+        }
+    }
+}
+
+/// Count a request with the given unknown user agent
+///
+/// # Errors
+///
+/// * the `UnknownUserAgents` Durable Object can't be reached
+pub async fn record(env: &Env, user_agent: &str) -> Result<()> {
+    let record = Command::Record {
+        user_agent: user_agent.to_string(),
+    };
+    durable::call(env, BINDING, NAME, "/record", &record).await
+}
+
+/// The `limit` most common unknown user agents
+///
+/// # Errors
+///
+/// * the `UnknownUserAgents` Durable Object can't be reached
+pub async fn top(env: &Env, limit: usize) -> Result<Vec<Entry>> {
+    durable::call(env, BINDING, NAME, "/top", &Command::Top { limit }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures_executor::block_on;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("MyCast/2.3.1 (iPhone12,1; iOS 15.1)"), "MyCast/");
+        assert_eq!(
+            normalize("MyCast/2.3 Android/30 Build/QQ3A"),
+            "MyCast/ Android/ Build/QQ3A"
+        );
+        assert_eq!(
+            normalize("Feeder 123e4567-e89b-12d3-a456-426614174000 v2"),
+            "Feeder"
+        );
+        assert_eq!(normalize("  1.0  "), "");
+        assert_eq!(normalize(&"x".repeat(300)).len(), MAX_LENGTH);
+    }
+
+    #[test]
+    fn test_record() {
+        let mut collector = Collector::new(MemoryStorage::default());
+        for user_agent in [
+            "MyCast/2.3.1 (iPhone12,1; iOS 15.1)",
+            "MyCast/2.4 (iPhone14,2; iOS 16.0)",
+            "OtherCast/1.0",
+            "1.0",
+        ] {
+            block_on(collector.record(user_agent)).unwrap();
+        }
+        assert_eq!(
+            block_on(collector.top(10)).unwrap(),
+            vec![
+                Entry {
+                    user_agent: "MyCast/".to_string(),
+                    count: 2,
+                    example: "MyCast/2.4 (iPhone14,2; iOS 16.0)".to_string(),
+                },
+                Entry {
+                    user_agent: "OtherCast/".to_string(),
+                    count: 1,
+                    example: "OtherCast/1.0".to_string(),
+                },
+            ]
+        );
+        assert_eq!(block_on(collector.top(1)).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_record_bounded() {
+        let mut collector = Collector::new(MemoryStorage::default());
+        for i in 0..=MAX_USER_AGENTS {
+            block_on(collector.record(&format!("Client{i}x/1.0"))).unwrap();
+        }
+        assert_eq!(collector.storage.0.len(), MAX_USER_AGENTS);
+    }
+
+    #[test]
+    fn test_opawg() {
+        let entry = Entry {
+            user_agent: "My.Cast/ Android/".to_string(),
+            count: 3,
+            example: "My.Cast/2.3 Android/30".to_string(),
+        };
+        assert_eq!(
+            entry.opawg(),
+            json!({
+                "user_agents": ["^My\\.Cast/ Android/"],
+                "app": "My.Cast",
+                "examples": ["My.Cast/2.3 Android/30"],
+            })
+        );
+    }
+}
@@ -0,0 +1,231 @@
+//! Sanity checks for rewritten feeds
+//!
+//! `Replacer::replace` works on the raw feed string, so nothing guarantees
+//! that the result is still a feed podcast apps can read. Before serving a
+//! rewritten feed we make sure that it is well-formed XML, that no enclosure
+//! got lost along the way and that every forwarding URL resolves back to the
+//! original mp3 file (without measurement prefixes if these get stripped).
+
+use crate::codec;
+use crate::rss::{is_rewritable, Replacer};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::fmt;
+use url::Url;
+
+/// Reasons for rejecting a rewritten feed
+#[derive(Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    /// The rewritten feed is not well-formed XML
+    Malformed(String),
+    /// The number of enclosures changed during the rewrite
+    EnclosureCount { input: usize, output: usize },
+    /// An mp3 enclosure was not replaced with a forwarding URL
+    NotRewritten(String),
+    /// A forwarding URL does not resolve to the original mp3 URL
+    RoundTrip { original: String, resolved: String },
+}
+
+impl ValidationError {
+    /// Short identifier of the failure, used as metric name in the logs
+    #[must_use]
+    pub const fn metric(&self) -> &'static str {
+        match self {
+            Self::Malformed(_) => "feed_validation.malformed",
+            Self::EnclosureCount { .. } => "feed_validation.enclosure_count",
+            Self::NotRewritten(_) => "feed_validation.not_rewritten",
+            Self::RoundTrip { .. } => "feed_validation.round_trip",
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed(e) => write!(f, "Rewritten feed is not well-formed XML: {e}"),
+            Self::EnclosureCount { input, output } => write!(
+                f,
+                "Enclosure count changed from {input} to {output} during rewrite"
+            ),
+            Self::NotRewritten(url) => write!(f, "Enclosure was not rewritten: {url}"),
+            Self::RoundTrip { original, resolved } => write!(
+                f,
+                "Forwarding URL for {original} resolves to {resolved} instead"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Check that the given input is well-formed XML
+fn well_formed(input: &str) -> Result<(), ValidationError> {
+    let mut reader = Reader::from_str(input);
+    reader.check_end_names(true);
+    let mut depth = 0_usize;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(_)) => depth += 1,
+            Ok(Event::End(_)) => depth = depth.saturating_sub(1),
+            Ok(Event::Eof) => break,
+            Ok(_) => {}
+            Err(e) => {
+                return Err(ValidationError::Malformed(format!(
+                    "{e} at position {}",
+                    reader.buffer_position()
+                )))
+            }
+        }
+    }
+    if depth > 0 {
+        return Err(ValidationError::Malformed(format!(
+            "{depth} unclosed element(s) at end of document"
+        )));
+    }
+    Ok(())
+}
+
+/// Validate a feed rewritten by `replacer` against the original `input`
+///
+/// # Errors
+///
+/// * the output is not well-formed XML
+/// * the output has a different number of enclosures than the input
+/// * an mp3 enclosure of the input was not rewritten
+/// * a rewritten enclosure does not resolve back to its original URL
+pub fn validate(input: &str, output: &str, replacer: &Replacer) -> Result<(), ValidationError> {
+    well_formed(output)?;
+
+    let originals = replacer.extract(input);
+    let rewritten = replacer.extract(output);
+    if originals.len() != rewritten.len() {
+        return Err(ValidationError::EnclosureCount {
+            input: originals.len(),
+            output: rewritten.len(),
+        });
+    }
+
+    // The replacer keeps the order of enclosures, so we can compare them
+    // pairwise
+    for (original, rewritten) in originals.iter().zip(rewritten.iter()) {
+        let Ok(original) = Url::parse(original) else {
+            continue;
+        };
+        if !is_rewritable(&original) || replacer.is_forwarded(&original) {
+            continue;
+        }
+        if original.as_str() == rewritten {
+            return Err(ValidationError::NotRewritten(original.to_string()));
+        }
+        match codec::decode(rewritten, replacer.path_prefix(), replacer.token_key()) {
+            Ok(resolved) if resolved == replacer.upstream(&original) => {}
+            Ok(resolved) => {
+                return Err(ValidationError::RoundTrip {
+                    original: original.to_string(),
+                    resolved: resolved.to_string(),
+                })
+            }
+            Err(e) => {
+                return Err(ValidationError::RoundTrip {
+                    original: original.to_string(),
+                    resolved: e.to_string(),
+                })
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{Mode, TokenKey};
+
+    use pretty_assertions::assert_eq;
+
+    fn replacer() -> Replacer {
+        Replacer::new(
+            Url::parse("http://example.com/podcast").unwrap(),
+            Url::parse("https://example.org").unwrap(),
+            Some("/r"),
+        )
+    }
+
+    #[test]
+    fn test_valid_rewrite() {
+        let input = r#"<rss><channel><link>https://example.com</link><item><enclosure url="https://example.com/podcast1.mp3?bla=blub123" type="audio/mpeg"/></item><item><enclosure url="https://example.com/podcast.link"/></item></channel></rss>"#;
+        let replacer = replacer();
+        let output = replacer.replace(input.to_string());
+        assert_eq!(validate(input, &output, &replacer), Ok(()));
+    }
+
+    #[test]
+    fn test_fixtures() {
+        for fixture in [
+            include_str!("../fixtures/doppelgaenger_20220205.rss"),
+            include_str!("../fixtures/engineering_kiosk_20220205.rss"),
+        ] {
+            for mode in [Mode::Ref, Mode::Token, Mode::Path] {
+                let replacer = replacer()
+                    .with_mode(mode)
+                    .with_token_key(TokenKey::new("secret"));
+                let output = replacer.replace(fixture.to_string());
+                assert_eq!(validate(fixture, &output, &replacer), Ok(()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_malformed() {
+        let input = "<rss><channel></channel></rss>";
+        let output = "<rss><channel></rss>";
+        assert!(matches!(
+            validate(input, output, &replacer()),
+            Err(ValidationError::Malformed(_))
+        ));
+        assert!(matches!(
+            validate(input, "<rss><channel>", &replacer()),
+            Err(ValidationError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_enclosure_count() {
+        let input = r#"<rss>
+            <enclosure url="https://example.com/1.mp3"/>
+            <enclosure url="https://example.com/2.mp3"/>
+        </rss>"#;
+        let output = r#"<rss><enclosure url="https://example.org/r/1.mp3?ref=https%3A%2F%2Fexample.com%2F1.mp3"/></rss>"#;
+        assert_eq!(
+            validate(input, output, &replacer()),
+            Err(ValidationError::EnclosureCount {
+                input: 2,
+                output: 1
+            })
+        );
+    }
+
+    #[test]
+    fn test_not_rewritten() {
+        let input = r#"<rss><enclosure url="https://example.com/1.mp3"/></rss>"#;
+        assert_eq!(
+            validate(input, input, &replacer()),
+            Err(ValidationError::NotRewritten(
+                "https://example.com/1.mp3".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let input = r#"<rss><enclosure url="https://example.com/1.mp3"/></rss>"#;
+        let output = r#"<rss><enclosure url="https://example.org/r/1.mp3?ref=https%3A%2F%2Fexample.com%2F2.mp3"/></rss>"#;
+        assert_eq!(
+            validate(input, output, &replacer()),
+            Err(ValidationError::RoundTrip {
+                original: "https://example.com/1.mp3".to_string(),
+                resolved: "https://example.com/2.mp3".to_string(),
+            })
+        );
+    }
+}